@@ -0,0 +1,72 @@
+// Biome textures blended over the terrain, see `utils::terrain_material`
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    pbr_fragment::pbr_input_from_standard_material,
+    pbr_functions::{alpha_discard, apply_pbr_lighting, main_pass_post_lighting_processing},
+}
+
+struct TerrainSettings {
+    origin: vec2<f32>,
+    texture_size: f32,
+    rock_layer: u32,
+    rock_steepness: vec2<f32>,
+    snow_height: vec2<f32>,
+    snow_steepness: f32,
+    view: u32,
+}
+
+@group(2) @binding(100) var<uniform> terrain: TerrainSettings;
+@group(2) @binding(101) var textures: texture_2d_array<f32>;
+@group(2) @binding(102) var textures_sampler: sampler;
+@group(2) @binding(103) var biomes: texture_2d<u32>;
+
+const VIEW_TEXTURES: u32 = 0u;
+// `tile::NO_BIOME`
+const NO_BIOME: u32 = 255u;
+
+// Texture layer of tile (x, z) of the chunk, tiles without a biome show rock
+fn tile_layer(x: i32, z: i32) -> u32 {
+    let last = vec2<i32>(textureDimensions(biomes)) - 1;
+    let biome = textureLoad(biomes, clamp(vec2(z, x), vec2(0), last), 0).r;
+    return select(biome, terrain.rock_layer, biome == NO_BIOME);
+}
+
+fn layer_color(uv: vec2<f32>, layer: u32) -> vec3<f32> {
+    return textureSample(textures, textures_sampler, uv, layer).rgb;
+}
+
+@fragment
+fn fragment(in: VertexOutput, @builtin(front_facing) is_front: bool) -> FragmentOutput {
+    var pbr_input = pbr_input_from_standard_material(in, is_front);
+
+    // Textures line up across chunks because they follow the world position
+    let uv = in.world_position.xz / terrain.texture_size;
+    // The four tiles around the fragment, weighted by how close it is to each of them
+    let tile = in.world_position.xz - terrain.origin;
+    let corner = vec2<i32>(floor(tile));
+    let weight = fract(tile);
+    var color = mix(
+        mix(layer_color(uv, tile_layer(corner.x, corner.y)), layer_color(uv, tile_layer(corner.x + 1, corner.y)), weight.x),
+        mix(layer_color(uv, tile_layer(corner.x, corner.y + 1)), layer_color(uv, tile_layer(corner.x + 1, corner.y + 1)), weight.x),
+        weight.y,
+    );
+
+    // Rock on steep slopes, snow on high ground that is not too steep
+    let steepness = 1.0 - normalize(in.world_normal).y;
+    let rock = smoothstep(terrain.rock_steepness.x, terrain.rock_steepness.y, steepness);
+    color = mix(color, layer_color(uv, terrain.rock_layer), rock);
+    let snow = smoothstep(terrain.snow_height.x, terrain.snow_height.y, in.world_position.y)
+        * (1.0 - smoothstep(terrain.snow_steepness * 0.5, terrain.snow_steepness, steepness));
+    color = mix(color, layer_color(uv, terrain.rock_layer + 1u), snow);
+
+    // The other views show the vertex colors `pbr_input_from_standard_material` already applied
+    if terrain.view == VIEW_TEXTURES {
+        pbr_input.material.base_color = vec4(color, 1.0);
+    }
+    pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
+
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+    return out;
+}
@@ -0,0 +1,254 @@
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use terrain_generation::export::geotiff::{write_geotiff, GeoTiffParams};
+use terrain_generation::export::heightmap::{write_heightmap, HeightmapParams};
+use terrain_generation::export::images::{write_layer_images, ColorRamp, ImageParams};
+use terrain_generation::export::layers::write_layers;
+use terrain_generation::export::mesh::write_mesh;
+use terrain_generation::world_generation::chunks::{ChunkCoord, ChunkedWorld};
+use terrain_generation::world_generation::meshing::terrain_mesh;
+use terrain_generation::world_generation::pipeline::generate_world_map_with_progress;
+use terrain_generation::world_generation::progress::{CancellationToken, Progress, ProgressUpdate};
+use terrain_generation::world_generation::save::save_world;
+use terrain_generation::world_generation::settings::GenerationSettings;
+// Headless world generation
+// Runs the same pipeline as the app without a window or GPU and writes the layers to disk
+// Build without the renderer with `cargo run --release --no-default-features --bin terragen -- --out world`
+
+const USAGE: &str = "Usage: terragen [--settings <file.ron>] [--seed <u32>] [--size <tiles>] [--out <directory>] [--save <file.world>]
+                [--heightmap <file.png|file.raw>] [--height-range <min>:<max>] [--resolution <pixels>]
+                [--images <directory>] [--temperature-ramp <ramp>] [--rainfall-ramp <ramp>]
+                [--geotiff <file.tif>] [--pixel-scale <metres>] [--geo-origin <x>:<y>]
+                [--mesh <file.glb|file.obj>] [--subdivisions <count>] [--chunks <x>:<z>:<count>] [--timeout <seconds>]
+Settings are read from resources/settings.ron unless --settings is given, --seed, --size and --subdivisions override them.
+--geotiff also writes the biome IDs with their colors to <file>_biome.tif.
+--chunks generates count x count chunks of the size in the chunk settings, starting at chunk (x, z), instead of the world.
+Chunks can lie outside of the world, noise is sampled at global tile coordinates scaled by --size.
+--timeout stops the generation if it takes longer than the given time.
+A ramp is a list of value:#rrggbb stops, e.g. -1:#0000ff,0:#ffffff,1:#ff0000";
+
+struct Args {
+    settings: GenerationSettings,
+    out: Option<PathBuf>,
+    save: Option<PathBuf>,
+    heightmap: Option<PathBuf>,
+    heightmap_params: HeightmapParams,
+    images: Option<PathBuf>,
+    image_params: ImageParams,
+    geotiff: Option<PathBuf>,
+    geotiff_params: GeoTiffParams,
+    mesh: Option<PathBuf>,
+    /// First chunk and number of chunks along each side
+    chunks: Option<(ChunkCoord, u32)>,
+    timeout: Option<Duration>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut settings_path = None;
+    let mut seed = None;
+    let mut size = None;
+    let mut subdivisions = None;
+    let mut out = None;
+    let mut save = None;
+    let mut heightmap = None;
+    let mut heightmap_params = HeightmapParams::default();
+    let mut images = None;
+    let mut image_params = ImageParams::default();
+    let mut geotiff = None;
+    let mut geotiff_params = GeoTiffParams::default();
+    let mut mesh = None;
+    let mut chunks = None;
+    let mut timeout = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--settings" => settings_path = Some(PathBuf::from(args.next().ok_or("--settings needs a value")?)),
+            "--seed" => seed = Some(parse_value(&arg, args.next())?),
+            "--size" => size = Some(parse_value(&arg, args.next())?),
+            "--out" => out = Some(PathBuf::from(args.next().ok_or("--out needs a value")?)),
+            "--save" => save = Some(PathBuf::from(args.next().ok_or("--save needs a value")?)),
+            "--heightmap" => heightmap = Some(PathBuf::from(args.next().ok_or("--heightmap needs a value")?)),
+            "--height-range" => {
+                let range = args.next().ok_or("--height-range needs a value")?;
+                let (min, max) = range.split_once(':').ok_or("--height-range expects <min>:<max>")?;
+                heightmap_params.min_height = parse_value(&arg, Some(min.to_string()))?;
+                heightmap_params.max_height = parse_value(&arg, Some(max.to_string()))?;
+            }
+            "--resolution" => {
+                let resolution = parse_value(&arg, args.next())?;
+                heightmap_params.resolution = Some(resolution);
+                image_params.resolution = Some(resolution);
+                geotiff_params.resolution = Some(resolution);
+            }
+            "--images" => images = Some(PathBuf::from(args.next().ok_or("--images needs a value")?)),
+            "--temperature-ramp" => image_params.temperature_ramp = parse_ramp(&arg, args.next())?,
+            "--rainfall-ramp" => image_params.rainfall_ramp = parse_ramp(&arg, args.next())?,
+            "--geotiff" => geotiff = Some(PathBuf::from(args.next().ok_or("--geotiff needs a value")?)),
+            "--pixel-scale" => geotiff_params.pixel_scale = parse_value(&arg, args.next())?,
+            "--geo-origin" => {
+                let origin = args.next().ok_or("--geo-origin needs a value")?;
+                let (x, y) = origin.split_once(':').ok_or("--geo-origin expects <x>:<y>")?;
+                geotiff_params.origin = Some((parse_value(&arg, Some(x.to_string()))?, parse_value(&arg, Some(y.to_string()))?));
+            }
+            "--mesh" => mesh = Some(PathBuf::from(args.next().ok_or("--mesh needs a value")?)),
+            "--subdivisions" => subdivisions = Some(parse_value(&arg, args.next())?),
+            "--chunks" => {
+                let area = args.next().ok_or("--chunks needs a value")?;
+                let [x, z, count] = area.split(':').collect::<Vec<_>>()[..] else {
+                    return Err("--chunks expects <x>:<z>:<count>".to_string());
+                };
+                let count = parse_value(&arg, Some(count.to_string()))?;
+                if count == 0 {
+                    return Err("--chunks needs at least one chunk".to_string());
+                }
+                chunks = Some((ChunkCoord::new(parse_value(&arg, Some(x.to_string()))?, parse_value(&arg, Some(z.to_string()))?), count));
+            }
+            "--timeout" => {
+                let seconds: f64 = parse_value(&arg, args.next())?;
+                if !(seconds > 0.0 && seconds.is_finite()) {
+                    return Err("--timeout must be a positive number of seconds".to_string());
+                }
+                timeout = Some(Duration::from_secs_f64(seconds));
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+
+    let mut settings = match settings_path {
+        Some(path) => GenerationSettings::load(&path).map_err(|err| format!("{}: {}", path.display(), err))?,
+        None => GenerationSettings::load_or_default(),
+    };
+    settings.seed = seed.unwrap_or(settings.seed);
+    settings.world_size = size.unwrap_or(settings.world_size);
+    settings.subdivisions = subdivisions.unwrap_or(settings.subdivisions);
+    settings.validate().map_err(|err| err.to_string())?;
+    if chunks.is_some_and(|(_, count): (ChunkCoord, u32)| count.checked_mul(settings.chunks.chunk_size).is_none()) {
+        return Err("--chunks covers too many tiles".to_string());
+    }
+
+    if out.is_none() && save.is_none() && heightmap.is_none() && images.is_none() && geotiff.is_none() && mesh.is_none() {
+        return Err("nothing to write, pass --out, --save, --heightmap, --images, --geotiff or --mesh".to_string());
+    }
+    Ok(Args {
+        settings,
+        out,
+        save,
+        heightmap,
+        heightmap_params,
+        images,
+        image_params,
+        geotiff,
+        geotiff_params,
+        mesh,
+        chunks,
+        timeout,
+    })
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or(format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+fn parse_ramp(flag: &str, value: Option<String>) -> Result<ColorRamp, String> {
+    let value = value.ok_or(format!("{} needs a value", flag))?;
+    ColorRamp::parse(&value).map_err(|message| format!("invalid value for {}: {}", flag, message))
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            exit(2);
+        }
+    };
+
+    let cancellation = CancellationToken::new();
+    if let Some(timeout) = args.timeout {
+        let cancellation = cancellation.clone();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            cancellation.cancel();
+        });
+    }
+    let progress = Progress::new(print_progress).with_cancellation(cancellation);
+
+    let start = Instant::now();
+    let world_map = match args.chunks {
+        Some((first, count)) => ChunkedWorld::new(args.settings.clone()).area(first, count, &progress),
+        None => generate_world_map_with_progress(&args.settings, &progress),
+    }
+    .unwrap_or_else(|_| stopped(start));
+    println!("WORLD GENERATED in {:.1?}", start.elapsed());
+
+    if let Some(out) = &args.out {
+        check_written(out, write_layers(&world_map, out));
+    }
+    if let Some(save) = &args.save {
+        check_written(save, save_world(&world_map, &args.settings, save));
+    }
+    if let Some(heightmap) = &args.heightmap {
+        check_written(heightmap, write_heightmap(&world_map, heightmap, &args.heightmap_params));
+    }
+    if let Some(images) = &args.images {
+        check_written(images, write_layer_images(&world_map, images, &args.image_params));
+    }
+    if let Some(geotiff) = &args.geotiff {
+        check_written(geotiff, write_geotiff(&world_map, geotiff, &args.geotiff_params));
+    }
+    if let Some(mesh) = &args.mesh {
+        progress.begin("Meshing and coloring").unwrap_or_else(|_| stopped(start));
+        let terrain = terrain_mesh(&world_map, args.settings.subdivisions);
+        progress.finish();
+        check_written(mesh, write_mesh(&terrain, mesh));
+    }
+}
+
+/// Draws a bar for the running stage if stderr is a terminal, otherwise prints a line per finished stage
+fn print_progress(update: &ProgressUpdate) {
+    const WIDTH: usize = 30;
+    if std::io::stderr().is_terminal() {
+        let filled = (update.fraction * WIDTH as f32) as usize;
+        eprint!(
+            "\r{:<28} [{}{}] {:>3.0}% {:>6.1}s",
+            update.stage,
+            "#".repeat(filled),
+            " ".repeat(WIDTH - filled),
+            update.fraction * 100.0,
+            update.stage_elapsed.as_secs_f32()
+        );
+        if update.fraction >= 1.0 {
+            eprintln!();
+        }
+    } else if update.fraction >= 1.0 {
+        eprintln!("{} done in {:.1?}", update.stage, update.stage_elapsed);
+    }
+}
+
+fn stopped(start: Instant) -> ! {
+    if std::io::stderr().is_terminal() {
+        eprintln!();
+    }
+    eprintln!("Stopped after {:.1?}, the timeout was reached", start.elapsed());
+    exit(1);
+}
+
+fn check_written(path: &Path, result: std::io::Result<()>) {
+    match result {
+        Ok(()) => println!("Written to {}", path.display()),
+        Err(error) => {
+            eprintln!("Could not write {}: {}", path.display(), error);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use rayon::prelude::*;
+use super::resample;
+use crate::world_generation::biomes::BiomeRegistry;
+use crate::world_generation::tile::{NO_BIOME, WorldMap};
+// GeoTIFF export for GIS tools
+// The climate layers are written as one deflate compressed float32 TIFF with a band per layer:
+//   1 height, 2 temperature, 3 rainfall
+// TIFF color tables only work for single band 8-bit images, so the biome IDs go into their own palette TIFF
+// `<file>_biome.tif` with the same georeferencing. TIFF has no field for category names, those go into the
+// GDAL sidecar `<file>_biome.tif.aux.xml`, which GDAL and QGIS read along with the TIFF
+// The georeferencing is a user defined projected system in metres with north towards -z
+
+/// Tunables of the GeoTIFF export
+#[derive(Debug, Clone)]
+pub struct GeoTiffParams {
+    /// Width of a pixel in metres
+    pub pixel_scale: f64,
+    /// Projected coordinates of the top left corner of the image.
+    /// If not set the world origin is used, so neighbouring chunks line up.
+    pub origin: Option<(f64, f64)>,
+    /// Width of the image in pixels, the size of the world map if not set
+    pub resolution: Option<u32>,
+}
+
+impl Default for GeoTiffParams {
+    fn default() -> Self {
+        GeoTiffParams {
+            pixel_scale: 1.0,
+            origin: None,
+            resolution: None,
+        }
+    }
+}
+
+/// Name of the coordinate system, GeoTIFF ends every text in GeoAsciiParamsTag with '|'
+const CITATION: &str = "terrain-generation fictional|";
+
+/// Rows compressed together, the unit GIS tools read at once
+const ROWS_PER_STRIP: usize = 16;
+
+// TIFF field types
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const ASCII: u16 = 2;
+const DOUBLE: u16 = 12;
+
+/// An entry of the image file directory, the value is already encoded little-endian
+struct Field {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    value: Vec<u8>,
+}
+
+impl Field {
+    fn shorts(tag: u16, values: &[u16]) -> Self {
+        Field { tag, field_type: SHORT, count: values.len() as u32, value: values.iter().flat_map(|v| v.to_le_bytes()).collect() }
+    }
+
+    fn longs(tag: u16, values: &[u32]) -> Self {
+        Field { tag, field_type: LONG, count: values.len() as u32, value: values.iter().flat_map(|v| v.to_le_bytes()).collect() }
+    }
+
+    fn doubles(tag: u16, values: &[f64]) -> Self {
+        Field { tag, field_type: DOUBLE, count: values.len() as u32, value: values.iter().flat_map(|v| v.to_le_bytes()).collect() }
+    }
+
+    fn ascii(tag: u16, text: &str) -> Self {
+        let mut value = text.as_bytes().to_vec();
+        value.push(0);
+        Field { tag, field_type: ASCII, count: value.len() as u32, value }
+    }
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "image is too large for TIFF, lower the resolution")
+}
+
+/// Writes a little-endian TIFF with a single image.
+/// `strips` are the compressed strips in the order of the StripOffsets field.
+fn write_tiff(path: &Path, mut fields: Vec<Field>, strips: &[Vec<u8>]) -> io::Result<()> {
+    // Header, then the strips, then values that do not fit into an entry, then the directory
+    let mut offset = 8u64;
+    let mut strip_offsets = Vec::with_capacity(strips.len());
+    for strip in strips {
+        strip_offsets.push(u32::try_from(offset).map_err(|_| too_large())?);
+        offset += strip.len() as u64;
+    }
+    fields.push(Field::longs(273, &strip_offsets));
+    fields.push(Field::longs(279, &strips.iter().map(|strip| strip.len() as u32).collect::<Vec<_>>()));
+    fields.sort_by_key(|field| field.tag);
+
+    let mut value_offsets = Vec::with_capacity(fields.len());
+    for field in &fields {
+        if field.value.len() > 4 {
+            // Values have to start on a word boundary
+            offset += offset % 2;
+            value_offsets.push(offset);
+            offset += field.value.len() as u64;
+        } else {
+            value_offsets.push(0);
+        }
+    }
+    offset += offset % 2;
+    let directory = u32::try_from(offset).map_err(|_| too_large())?;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(b"II")?;
+    writer.write_all(&42u16.to_le_bytes())?;
+    writer.write_all(&directory.to_le_bytes())?;
+
+    let mut position = 8u64;
+    for strip in strips {
+        writer.write_all(strip)?;
+        position += strip.len() as u64;
+    }
+    for (field, &value_offset) in fields.iter().zip(&value_offsets) {
+        if value_offset != 0 {
+            if position < value_offset {
+                writer.write_all(&[0])?;
+            }
+            writer.write_all(&field.value)?;
+            position = value_offset + field.value.len() as u64;
+        }
+    }
+    if position < directory as u64 {
+        writer.write_all(&[0])?;
+    }
+
+    writer.write_all(&(fields.len() as u16).to_le_bytes())?;
+    for (field, &value_offset) in fields.iter().zip(&value_offsets) {
+        writer.write_all(&field.tag.to_le_bytes())?;
+        writer.write_all(&field.field_type.to_le_bytes())?;
+        writer.write_all(&field.count.to_le_bytes())?;
+        if value_offset != 0 {
+            writer.write_all(&(value_offset as u32).to_le_bytes())?;
+        } else {
+            let mut inline = [0u8; 4];
+            inline[..field.value.len()].copy_from_slice(&field.value);
+            writer.write_all(&inline)?;
+        }
+    }
+    // No further images
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.flush()
+}
+
+/// Splits a band stored rows first into deflate compressed strips
+fn compress_strips(band: &[u8], row_bytes: usize) -> io::Result<Vec<Vec<u8>>> {
+    band.par_chunks(row_bytes * ROWS_PER_STRIP)
+        .map(|strip| {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(strip)?;
+            encoder.finish()
+        })
+        .collect()
+}
+
+/// Fields of the image size, strips, compression and georeferencing
+fn common_fields(resolution: usize, bits: &[u16], pixel_scale: f64, origin: (f64, f64)) -> Vec<Field> {
+    let size = resolution as u32;
+    vec![
+        Field::longs(256, &[size]),
+        Field::longs(257, &[size]),
+        Field::shorts(258, bits),
+        // Adobe deflate
+        Field::shorts(259, &[8]),
+        Field::shorts(277, &[bits.len() as u16]),
+        Field::longs(278, &[ROWS_PER_STRIP as u32]),
+        // ModelPixelScaleTag, rows go south
+        Field::doubles(33550, &[pixel_scale, pixel_scale, 0.0]),
+        // ModelTiepointTag, pixel (0, 0) is at the origin
+        Field::doubles(33922, &[0.0, 0.0, 0.0, origin.0, origin.1, 0.0]),
+        // GeoKeyDirectoryTag: version 1.1.0 with 5 keys
+        Field::shorts(34735, &[
+            1, 1, 0, 5,
+            // GTModelTypeGeoKey: projected
+            1024, 0, 1, 1,
+            // GTRasterTypeGeoKey: pixel is area
+            1025, 0, 1, 1,
+            // GTCitationGeoKey, in GeoAsciiParamsTag
+            1026, 34737, CITATION.len() as u16, 0,
+            // ProjectedCSTypeGeoKey: user defined
+            3072, 0, 1, 32767,
+            // ProjLinearUnitsGeoKey: metre
+            3076, 0, 1, 9001,
+        ]),
+        Field::ascii(34737, CITATION),
+    ]
+}
+
+/// GDAL sidecar of the GeoTIFF at `path`, `<file>.aux.xml`
+pub fn aux_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".aux.xml");
+    path.with_file_name(name)
+}
+
+/// Biome GeoTIFF written next to the GeoTIFF at `path`, `<file>_biome.tif`
+pub fn biome_path(path: &Path) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push("_biome.");
+    name.push(path.extension().unwrap_or("tif".as_ref()));
+    path.with_file_name(name)
+}
+
+/// Writes the height, temperature and rainfall bands to `path`,
+/// the biome IDs to `biome_path(path)` and their names to the sidecar of that file
+pub fn write_geotiff(world_map: &WorldMap, path: &Path, params: &GeoTiffParams) -> io::Result<()> {
+    let size = world_map.get_size() as usize;
+    let resolution = params.resolution.map_or(size, |resolution| resolution.max(2) as usize);
+    let pixel_scale = params.pixel_scale * size as f64 / resolution as f64;
+    let origin = params.origin.unwrap_or_else(|| {
+        let (x, z) = world_map.origin();
+        (x as f64 * params.pixel_scale, (-z) as f64 * params.pixel_scale)
+    });
+
+    // Planar configuration, every band is stored as its own image, rows first
+    let mut strips = Vec::new();
+    for values in [
+        resample(&world_map.heights, size, resolution),
+        resample(&world_map.temperature_plane(), size, resolution),
+        resample(&world_map.rainfall_plane(), size, resolution),
+    ] {
+        let mut band = Vec::with_capacity(resolution * resolution * 4);
+        for row in 0..resolution {
+            for column in 0..resolution {
+                band.extend_from_slice(&values[column * resolution + row].to_le_bytes());
+            }
+        }
+        strips.extend(compress_strips(&band, resolution * 4)?);
+    }
+
+    let mut fields = common_fields(resolution, &[32; 3], pixel_scale, origin);
+    fields.extend([
+        // Black is zero
+        Field::shorts(262, &[1]),
+        // Planar configuration: separate bands
+        Field::shorts(284, &[2]),
+        // Extra samples: two unspecified bands
+        Field::shorts(338, &[0; 2]),
+        // Sample format: IEEE float
+        Field::shorts(339, &[3; 3]),
+        Field::ascii(270, "height, temperature, rainfall"),
+        // GDAL_METADATA with the band names
+        Field::ascii(42112, &gdal_metadata(&["height", "temperature", "rainfall"])),
+    ]);
+    write_tiff(path, fields, &strips)?;
+
+    // Biomes by nearest tile, resizing must not blend IDs
+    let to_tile = |pixel: usize| ((pixel * size + size / 2) / resolution).min(size - 1);
+    let mut biomes = Vec::with_capacity(resolution * resolution);
+    for row in 0..resolution {
+        for column in 0..resolution {
+            biomes.push(world_map.biomes[to_tile(column) * size + to_tile(row)]);
+        }
+    }
+    let mut fields = common_fields(resolution, &[8], pixel_scale, origin);
+    fields.extend([
+        // Palette color
+        Field::shorts(262, &[3]),
+        Field::shorts(320, &biome_color_map()),
+        // Sample format: unsigned integer
+        Field::shorts(339, &[1]),
+        Field::ascii(270, "biome"),
+        Field::ascii(42112, &gdal_metadata(&["biome"])),
+        // GDAL_NODATA
+        Field::ascii(42113, &NO_BIOME.to_string()),
+    ]);
+    let biome_path = biome_path(path);
+    write_tiff(&biome_path, fields, &compress_strips(&biomes, resolution)?)?;
+    std::fs::write(aux_path(&biome_path), biome_aux_xml())
+}
+
+/// TIFF color map of the biome IDs, all red values, then green, then blue, scaled to 16 bits
+fn biome_color_map() -> Vec<u16> {
+    let mut colors = vec![0u16; 3 * 256];
+    for (biome, def) in BiomeRegistry::global().iter() {
+        for (channel, &value) in def.color.iter().enumerate() {
+            colors[channel * 256 + biome.0 as usize] = value as u16 * 257;
+        }
+    }
+    colors
+}
+
+/// Names of the bands in the GDAL_METADATA format
+fn gdal_metadata(names: &[&str]) -> String {
+    let items: String = names
+        .iter()
+        .enumerate()
+        .map(|(sample, name)| format!("<Item name=\"DESCRIPTION\" sample=\"{}\" role=\"description\">{}</Item>", sample, name))
+        .collect();
+    format!("<GDALMetadata>{}</GDALMetadata>", items)
+}
+
+/// GDAL sidecar with the names of the biome IDs, the colors are in the TIFF itself
+fn biome_aux_xml() -> String {
+    let mut xml = String::from("<PAMDataset>\n  <PAMRasterBand band=\"1\">\n    <CategoryNames>\n");
+    for (_, biome) in BiomeRegistry::global().iter() {
+        let name = biome.name.replace('&', "&amp;").replace('<', "&lt;");
+        xml.push_str(&format!("      <Category>{}</Category>\n", name));
+    }
+    xml.push_str("    </CategoryNames>\n  </PAMRasterBand>\n</PAMDataset>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+    use super::*;
+
+    /// Fields of the first directory of a little-endian TIFF by tag, with their type, count and value
+    fn read_fields(bytes: &[u8]) -> BTreeMap<u16, (u16, u32, Vec<u8>)> {
+        let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(&bytes[..4], b"II\x2a\x00");
+        let directory = u32_at(4) as usize;
+        let count = u16_at(directory) as usize;
+        assert_eq!(u32_at(directory + 2 + count * 12), 0, "only one image");
+
+        let mut fields = BTreeMap::new();
+        for entry in (0..count).map(|i| directory + 2 + i * 12) {
+            let (field_type, count) = (u16_at(entry + 2), u32_at(entry + 4));
+            let length = count as usize * match field_type {
+                ASCII => 1,
+                SHORT => 2,
+                LONG => 4,
+                DOUBLE => 8,
+                other => panic!("unexpected field type {}", other),
+            };
+            let start = if length > 4 { u32_at(entry + 8) as usize } else { entry + 8 };
+            fields.insert(u16_at(entry), (field_type, count, bytes[start..start + length].to_vec()));
+        }
+        fields
+    }
+
+    fn shorts(value: &[u8]) -> Vec<u16> {
+        value.chunks(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect()
+    }
+
+    fn longs(value: &[u8]) -> Vec<u32> {
+        value.chunks(4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap())).collect()
+    }
+
+    /// Decompressed contents of every strip of the image
+    fn strips(bytes: &[u8], fields: &BTreeMap<u16, (u16, u32, Vec<u8>)>) -> Vec<Vec<u8>> {
+        let offsets = longs(&fields[&273].2);
+        let lengths = longs(&fields[&279].2);
+        assert_eq!(offsets.len(), lengths.len());
+        offsets
+            .iter()
+            .zip(&lengths)
+            .map(|(&offset, &length)| {
+                let mut strip = Vec::new();
+                ZlibDecoder::new(&bytes[offset as usize..(offset + length) as usize]).read_to_end(&mut strip).unwrap();
+                strip
+            })
+            .collect()
+    }
+
+    #[test]
+    fn biomes_are_a_palette_image_with_the_same_georeferencing() {
+        let size = 20;
+        let mut world_map = WorldMap::new(size);
+        for (i, biome) in world_map.biomes.iter_mut().enumerate() {
+            *biome = (i % 7) as u8;
+        }
+        world_map.biomes[0] = NO_BIOME;
+        let directory = std::env::temp_dir().join(format!("terrain-geotiff-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("world.tif");
+        write_geotiff(&world_map, &path, &GeoTiffParams { pixel_scale: 30.0, ..Default::default() }).unwrap();
+        let climate = std::fs::read(&path).unwrap();
+        let biomes = std::fs::read(directory.join("world_biome.tif")).unwrap();
+        let names = std::fs::read_to_string(directory.join("world_biome.tif.aux.xml")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let climate_fields = read_fields(&climate);
+        let biome_fields = read_fields(&biomes);
+        let georeferencing = [33550, 33922, 34735, 34737];
+        assert_eq!(
+            climate_fields.keys().copied().collect::<Vec<_>>(),
+            [256, 257, 258, 259, 262, 270, 273, 277, 278, 279, 284, 338, 339, 33550, 33922, 34735, 34737, 42112],
+        );
+        assert_eq!(
+            biome_fields.keys().copied().collect::<Vec<_>>(),
+            [256, 257, 258, 259, 262, 270, 273, 277, 278, 279, 320, 339, 33550, 33922, 34735, 34737, 42112, 42113],
+        );
+        for tag in georeferencing {
+            assert_eq!(climate_fields[&tag], biome_fields[&tag], "tag {}", tag);
+        }
+        let geo_keys = shorts(&biome_fields[&34735].2);
+        assert_eq!(geo_keys[..4], [1, 1, 0, 5]);
+        assert_eq!(geo_keys[4..].chunks(4).map(|key| key[0]).collect::<Vec<_>>(), [1024, 1025, 1026, 3072, 3076]);
+
+        // Two strips of 16 rows per band
+        let strips_per_band = size.div_ceil(ROWS_PER_STRIP as u32) as usize;
+        assert_eq!(shorts(&climate_fields[&277].2), [3]);
+        assert_eq!(strips(&climate, &climate_fields).len(), 3 * strips_per_band);
+        let bands = strips(&climate, &climate_fields).concat();
+        assert_eq!(bands.len(), 3 * (size * size) as usize * 4);
+
+        assert_eq!(shorts(&biome_fields[&258].2), [8]);
+        assert_eq!(shorts(&biome_fields[&262].2), [3]);
+        let pixels = strips(&biomes, &biome_fields).concat();
+        assert_eq!(pixels.len(), (size * size) as usize);
+        for (row, column) in [(0, 0), (3, 5), (19, 2)] {
+            let tile = column * size as usize + row;
+            assert_eq!(pixels[row * size as usize + column], world_map.biomes[tile]);
+        }
+
+        let color_map = shorts(&biome_fields[&320].2);
+        assert_eq!(biome_fields[&320].1, 3 * 256);
+        for (biome, def) in BiomeRegistry::global().iter() {
+            let id = biome.0 as usize;
+            let color = [color_map[id], color_map[256 + id], color_map[512 + id]];
+            assert_eq!(color, def.color.map(|value| value as u16 * 257));
+        }
+        assert_eq!(names.matches("<Category>").count(), BiomeRegistry::global().iter().count());
+        assert!(!names.contains("ColorTable"));
+    }
+}
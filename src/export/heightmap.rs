@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use super::resample;
+use crate::world_generation::tile::{DEEP_OCEAN, MAX_HEIGHT, WorldMap};
+// 16-bit heightmaps for other engines and GIS tools
+// Heights are mapped linearly from the height range to 0 - 65535, heights outside the range are clamped
+// Pixel (column, row) is tile (x, z), the same as looking down on the terrain in the app
+
+/// Tunables of the heightmap export
+#[derive(Debug, Clone)]
+pub struct HeightmapParams {
+    /// Height written as 0
+    pub min_height: f32,
+    /// Height written as 65535
+    pub max_height: f32,
+    /// Width of the written heightmap in pixels, the size of the world map if not set.
+    /// Unity expects a power of two plus one, e.g. 4097.
+    pub resolution: Option<u32>,
+}
+
+impl Default for HeightmapParams {
+    fn default() -> Self {
+        HeightmapParams {
+            min_height: DEEP_OCEAN,
+            max_height: MAX_HEIGHT,
+            resolution: None,
+        }
+    }
+}
+
+/// Normalized heights in image order, rows first. Returns the width of the image and the pixels.
+pub fn heightmap_pixels(world_map: &WorldMap, params: &HeightmapParams) -> (u32, Vec<u16>) {
+    let size = world_map.get_size() as usize;
+    let resolution = params.resolution.map_or(size, |resolution| resolution.max(2) as usize);
+    let heights = resample(&world_map.heights, size, resolution);
+    let range = (params.max_height - params.min_height).max(f32::EPSILON);
+
+    let mut pixels = vec![0u16; resolution * resolution];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let (z, x) = (i / resolution, i % resolution);
+        let normalized = ((heights[x * resolution + z] - params.min_height) / range).clamp(0.0, 1.0);
+        *pixel = (normalized * u16::MAX as f32).round() as u16;
+    }
+    (resolution as u32, pixels)
+}
+
+/// Writes the heights as a 16-bit grayscale PNG
+pub fn write_heightmap_png(world_map: &WorldMap, path: &Path, params: &HeightmapParams) -> io::Result<()> {
+    let (resolution, pixels) = heightmap_pixels(world_map, params);
+
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), resolution, resolution);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Sixteen);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+
+    // PNG stores 16-bit samples big-endian
+    let data: Vec<u8> = pixels.iter().flat_map(|pixel| pixel.to_be_bytes()).collect();
+    writer.write_image_data(&data).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)
+}
+
+/// Writes the heights as headerless 16-bit little-endian RAW, the format of Unity and Unreal terrains
+pub fn write_heightmap_raw(world_map: &WorldMap, path: &Path, params: &HeightmapParams) -> io::Result<()> {
+    let (_, pixels) = heightmap_pixels(world_map, params);
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    for pixel in pixels {
+        writer.write_all(&pixel.to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+/// Picks the format from the file extension, `.png` or `.raw`/`.r16`
+pub fn write_heightmap(world_map: &WorldMap, path: &Path, params: &HeightmapParams) -> io::Result<()> {
+    match path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase).as_deref() {
+        Some("png") => write_heightmap_png(world_map, path, params),
+        Some("raw") | Some("r16") => write_heightmap_raw(world_map, path, params),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown heightmap format {}, expected .png, .raw or .r16", path.display()),
+        )),
+    }
+}
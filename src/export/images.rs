@@ -0,0 +1,270 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use super::resample;
+use crate::world_generation::biomes::BiomeRegistry;
+use crate::world_generation::tile::{Biome, NO_BIOME, WorldMap};
+// 2D images of the world map layers
+// Pixel (column, row) is tile (x, z), the same as the heightmap export
+// Everything here only depends on the world map, the same world always gives the same bytes
+
+/// Colors at increasing values, values in between are interpolated and values outside get the nearest end
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorRamp {
+    pub stops: Vec<(f32, [u8; 3])>,
+}
+
+impl ColorRamp {
+    /// Cold blue to hot red, over the -1.0 - 1.0 range of the temperature layer
+    pub fn temperature() -> Self {
+        ColorRamp {
+            stops: vec![
+                (-1.0, [49, 54, 149]),
+                (-0.5, [116, 173, 209]),
+                (0.0, [255, 255, 191]),
+                (0.5, [244, 109, 67]),
+                (1.0, [165, 0, 38]),
+            ],
+        }
+    }
+
+    /// Dry brown to wet blue, over the 0.0 - 1.0 range of the rainfall layer
+    pub fn rainfall() -> Self {
+        ColorRamp {
+            stops: vec![
+                (0.0, [166, 97, 26]),
+                (0.25, [223, 194, 125]),
+                (0.5, [128, 205, 193]),
+                (0.75, [1, 133, 113]),
+                (1.0, [8, 48, 107]),
+            ],
+        }
+    }
+
+    /// Parses stops written as `value:#rrggbb`, separated by commas, e.g. `0:#000000,1:#ffffff`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut stops = Vec::new();
+        for stop in text.split(',') {
+            let (value, color) = stop.trim().split_once(':').ok_or(format!("expected value:#rrggbb, got {}", stop))?;
+            let value: f32 = value.trim().parse().map_err(|_| format!("invalid value {}", value))?;
+            let hex = color.trim().trim_start_matches('#');
+            if hex.len() != 6 {
+                return Err(format!("invalid color {}", color));
+            }
+            let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("invalid color {}", color));
+            stops.push((value, [channel(0)?, channel(2)?, channel(4)?]));
+        }
+        if stops.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err("stops must have increasing values".to_string());
+        }
+        Ok(ColorRamp { stops })
+    }
+
+    pub fn sample(&self, value: f32) -> [u8; 3] {
+        let Some(first) = self.stops.first() else {
+            return [0, 0, 0];
+        };
+        if value <= first.0 {
+            return first.1;
+        }
+        for pair in self.stops.windows(2) {
+            let ((low, low_color), (high, high_color)) = (pair[0], pair[1]);
+            if value <= high {
+                let t = (value - low) / (high - low);
+                return [0, 1, 2].map(|c| (low_color[c] as f32 + (high_color[c] as f32 - low_color[c] as f32) * t).round() as u8);
+            }
+        }
+        self.stops[self.stops.len() - 1].1
+    }
+}
+
+/// Tunables of the image export
+#[derive(Debug, Clone)]
+pub struct ImageParams {
+    /// Width of the images in pixels, the size of the world map if not set
+    pub resolution: Option<u32>,
+    pub temperature_ramp: ColorRamp,
+    pub rainfall_ramp: ColorRamp,
+    /// Direction the light comes from in degrees, clockwise from -z
+    pub sun_azimuth: f32,
+    /// Angle of the light above the horizon in degrees
+    pub sun_altitude: f32,
+    /// Exaggerates the heights for the shading
+    pub z_factor: f32,
+}
+
+impl Default for ImageParams {
+    fn default() -> Self {
+        ImageParams {
+            resolution: None,
+            temperature_ramp: ColorRamp::temperature(),
+            rainfall_ramp: ColorRamp::rainfall(),
+            sun_azimuth: 315.0,
+            sun_altitude: 45.0,
+            z_factor: 1.0,
+        }
+    }
+}
+
+/// An RGB image, rows first
+pub struct Image {
+    pub width: u32,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    /// Builds the image from a function of the pixel column and row
+    fn from_fn(width: usize, pixel: impl Fn(usize, usize) -> [u8; 3]) -> Self {
+        let pixels = (0..width * width).map(|i| pixel(i % width, i / width)).collect();
+        Image { width: width as u32, pixels }
+    }
+
+    pub fn write_png(&self, path: &Path) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), self.width, self.width);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(io::Error::other)?;
+        writer.write_image_data(self.pixels.as_flattened()).map_err(io::Error::other)?;
+        writer.finish().map_err(io::Error::other)
+    }
+}
+
+fn resolution(world_map: &WorldMap, params: &ImageParams) -> usize {
+    params.resolution.map_or(world_map.get_size() as usize, |resolution| resolution.max(2) as usize)
+}
+
+/// Biome of every pixel in the colors of `Biome::color`, the nearest tile is used when resizing
+pub fn biome_image(world_map: &WorldMap, params: &ImageParams) -> Image {
+    let registry = BiomeRegistry::global();
+    let size = world_map.get_size() as usize;
+    let resolution = resolution(world_map, params);
+    let to_tile = |pixel: usize| ((pixel * size + size / 2) / resolution).min(size - 1);
+
+    Image::from_fn(resolution, |column, row| {
+        match world_map.biomes[to_tile(column) * size + to_tile(row)] {
+            NO_BIOME => [0, 0, 0],
+            id => registry.get(Biome(id)).color,
+        }
+    })
+}
+
+/// A layer stored as `x * size + z` colored with a ramp
+pub fn ramp_image(plane: &[f32], size: usize, ramp: &ColorRamp, resolution: usize) -> Image {
+    let values = resample(plane, size, resolution);
+    Image::from_fn(resolution, |column, row| ramp.sample(values[column * resolution + row]))
+}
+
+pub fn temperature_image(world_map: &WorldMap, params: &ImageParams) -> Image {
+    let size = world_map.get_size() as usize;
+    ramp_image(&world_map.temperature_plane(), size, &params.temperature_ramp, resolution(world_map, params))
+}
+
+pub fn rainfall_image(world_map: &WorldMap, params: &ImageParams) -> Image {
+    let size = world_map.get_size() as usize;
+    ramp_image(&world_map.rainfall_plane(), size, &params.rainfall_ramp, resolution(world_map, params))
+}
+
+/// Biome colors lit by the sun, water surfaces are shaded flat
+pub fn shaded_relief_image(world_map: &WorldMap, params: &ImageParams) -> Image {
+    let size = world_map.get_size() as usize;
+    let resolution = resolution(world_map, params);
+    let surface: Vec<f32> = world_map.heights.iter().zip(&world_map.water_levels).map(|(height, water)| height.max(*water)).collect();
+    let surface = resample(&surface, size, resolution);
+    let biomes = biome_image(world_map, params);
+
+    // Lambert shading with the sun direction, the distance between pixels grows when shrinking the map
+    let spacing = size as f32 / resolution as f32;
+    let (azimuth, altitude) = (params.sun_azimuth.to_radians(), params.sun_altitude.to_radians());
+    let sun = [altitude.cos() * azimuth.sin(), altitude.sin(), -altitude.cos() * azimuth.cos()];
+    let height = |column: usize, row: usize| surface[column.min(resolution - 1) * resolution + row.min(resolution - 1)];
+
+    Image::from_fn(resolution, |column, row| {
+        let dx = (height(column + 1, row) - height(column.saturating_sub(1), row)) * params.z_factor / (2.0 * spacing);
+        let dz = (height(column, row + 1) - height(column, row.saturating_sub(1))) * params.z_factor / (2.0 * spacing);
+        let length = (dx * dx + 1.0 + dz * dz).sqrt();
+        let light = ((-dx * sun[0] + sun[1] - dz * sun[2]) / length).max(0.0);
+        // Keep some ambient light so shadowed slopes still show their biome
+        let shade = 0.3 + 0.7 * light / sun[1];
+        biomes.pixels[row * resolution + column].map(|channel| (channel as f32 * shade).round().clamp(0.0, 255.0) as u8)
+    })
+}
+
+/// Writes biome.png, temperature.png, rainfall.png and relief.png into `dir`, creating it if needed
+pub fn write_layer_images(world_map: &WorldMap, dir: &Path, params: &ImageParams) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    biome_image(world_map, params).write_png(&dir.join("biome.png"))?;
+    temperature_image(world_map, params).write_png(&dir.join("temperature.png"))?;
+    rainfall_image(world_map, params).write_png(&dir.join("rainfall.png"))?;
+    shaded_relief_image(world_map, params).write_png(&dir.join("relief.png"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::world_generation::tile::{quantize, TEMPERATURE_RANGE};
+
+    #[test]
+    fn parses_ramps() {
+        let ramp = ColorRamp::parse("-1:#0000ff, 0:#FFFFFF,1.5:ff0000").unwrap();
+        assert_eq!(ramp.stops, [(-1.0, [0, 0, 255]), (0.0, [255, 255, 255]), (1.5, [255, 0, 0])]);
+        assert_eq!(ramp.sample(-2.0), [0, 0, 255]);
+        assert_eq!(ramp.sample(-0.5), [128, 128, 255]);
+        assert_eq!(ramp.sample(2.0), [255, 0, 0]);
+
+        for invalid in ["", "0", "0:#fff", "0:#gggggg", "x:#000000", "1:#000000,0:#ffffff", "0:#000000,0:#ffffff"] {
+            assert!(ColorRamp::parse(invalid).is_err(), "{:?} should not parse", invalid);
+        }
+    }
+
+    /// A hill rising from the sea in the middle of the map, colder towards +x and wetter towards +z
+    fn small_world() -> WorldMap {
+        let size = 24;
+        let mut world_map = WorldMap::new(size);
+        let sea_level = world_map.sea_level();
+        let registry = BiomeRegistry::global();
+        let center = (size - 1) as f32 / 2.0;
+        for x in 0..size as usize {
+            for z in 0..size as usize {
+                let distance = ((x as f32 - center).powi(2) + (z as f32 - center).powi(2)).sqrt();
+                let height = sea_level + 120.0 - distance * 12.0;
+                let temperature = 0.8 - x as f32 / size as f32 * 1.6;
+                let rainfall = z as f32 / size as f32;
+                let i = world_map.index(x, z);
+                world_map.set_height(x, z, height);
+                world_map.temperatures[i] = quantize(temperature, TEMPERATURE_RANGE);
+                world_map.set_rainfall(x, z, rainfall);
+                world_map.water_levels[i] = height.max(sea_level);
+                world_map.biomes[i] = registry.classify(height, temperature, rainfall, sea_level).0;
+            }
+        }
+        world_map
+    }
+
+    /// Compares the image to `tests/golden/<name>`, run with `UPDATE_GOLDEN=1` to write it instead
+    fn assert_golden(image: &Image, name: &str) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            image.write_png(&path).unwrap();
+            return;
+        }
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut golden = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut golden).unwrap();
+        assert_eq!((info.width, info.color_type), (image.width, png::ColorType::Rgb), "{}", name);
+        let differing = golden.chunks(3).zip(&image.pixels).filter(|(golden, pixel)| golden != &pixel.as_slice()).count();
+        assert_eq!(differing, 0, "{} pixels of {} differ, rerun with UPDATE_GOLDEN=1 if the change is intended", differing, name);
+    }
+
+    #[test]
+    fn biome_and_relief_images_match_the_golden_files() {
+        let world_map = small_world();
+        let params = ImageParams::default();
+        assert_golden(&biome_image(&world_map, &params), "biome.png");
+        assert_golden(&shaded_relief_image(&world_map, &params), "relief.png");
+        assert_golden(&shaded_relief_image(&world_map, &ImageParams { resolution: Some(16), ..params }), "relief_16.png");
+    }
+}
@@ -0,0 +1,39 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::world_generation::biomes::BiomeRegistry;
+use crate::world_generation::tile::WorldMap;
+// Raw layer dump
+// Every layer is written as its own headerless little-endian plane in the same order as in memory, `x * size + z`
+// `world.txt` describes the size, origin and the files, so the planes can be read back by any tool
+
+/// Writes the layers of the world map into `dir`, creating it if needed
+pub fn write_layers(world_map: &WorldMap, dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    write_plane(&dir.join("height.f32"), &world_map.heights)?;
+    write_plane(&dir.join("temperature.f32"), &world_map.temperature_plane())?;
+    write_plane(&dir.join("rainfall.f32"), &world_map.rainfall_plane())?;
+    write_plane(&dir.join("water_level.f32"), &world_map.water_levels)?;
+    fs::write(dir.join("biome.u8"), &world_map.biomes)?;
+
+    let (origin_x, origin_z) = world_map.origin();
+    let mut info = BufWriter::new(File::create(dir.join("world.txt"))?);
+    writeln!(info, "size {}", world_map.get_size())?;
+    writeln!(info, "origin {} {}", origin_x, origin_z)?;
+    writeln!(info, "layout x * size + z, little-endian")?;
+    writeln!(info, "layers height.f32 temperature.f32 rainfall.f32 water_level.f32 biome.u8")?;
+    for (id, biome) in BiomeRegistry::global().iter() {
+        writeln!(info, "biome {} {}", id.0, biome.name)?;
+    }
+    info.flush()
+}
+
+fn write_plane(path: &Path, plane: &[f32]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for value in plane {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.flush()
+}
@@ -0,0 +1,118 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde_json::json;
+use crate::world_generation::meshing::TerrainMesh;
+// Terrain mesh export for Blender and other 3D tools
+// glTF 2.0 binary keeps positions, normals and vertex colors in a single .glb
+// Wavefront OBJ has no standard vertex colors, they are written as `v x y z r g b` which Blender and MeshLab read
+
+/// Writes the mesh as glTF 2.0 binary
+pub fn write_glb(mesh: &TerrainMesh, path: &Path) -> io::Result<()> {
+    // Binary buffer: positions, normals, colors, indices, every part is a multiple of 4 bytes
+    let mut buffer = Vec::new();
+    let mut views = Vec::new();
+    let mut add_view = |bytes: Vec<u8>, target: u32| {
+        views.push(json!({ "buffer": 0, "byteOffset": buffer.len(), "byteLength": bytes.len(), "target": target }));
+        buffer.extend(bytes);
+    };
+    add_view(mesh.positions.iter().flatten().flat_map(|v| v.to_le_bytes()).collect(), 34962);
+    add_view(mesh.normals.iter().flatten().flat_map(|v| v.to_le_bytes()).collect(), 34962);
+    add_view(mesh.colors.iter().flatten().flat_map(|v| v.to_le_bytes()).collect(), 34962);
+    add_view(mesh.indices.iter().flat_map(|v| v.to_le_bytes()).collect(), 34963);
+
+    // glTF requires the bounds of the positions
+    let (min, max) = mesh.bounds();
+    let vertices = mesh.positions.len();
+    let document = json!({
+        "asset": { "version": "2.0", "generator": concat!("terrain-generation ", env!("CARGO_PKG_VERSION")) },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "mesh": 0, "name": "Terrain" }],
+        "meshes": [{
+            "name": "Terrain",
+            "primitives": [{
+                "attributes": { "POSITION": 0, "NORMAL": 1, "COLOR_0": 2 },
+                "indices": 3,
+                "material": 0,
+            }],
+        }],
+        "materials": [{
+            "name": "Terrain",
+            "pbrMetallicRoughness": { "baseColorFactor": [1.0, 1.0, 1.0, 1.0], "metallicFactor": 0.0, "roughnessFactor": 1.0 },
+        }],
+        "buffers": [{ "byteLength": buffer.len() }],
+        "bufferViews": views,
+        "accessors": [
+            // 5126 is float, 5125 is unsigned int
+            { "bufferView": 0, "componentType": 5126, "count": vertices, "type": "VEC3", "min": min, "max": max },
+            { "bufferView": 1, "componentType": 5126, "count": vertices, "type": "VEC3" },
+            { "bufferView": 2, "componentType": 5126, "count": vertices, "type": "VEC4" },
+            { "bufferView": 3, "componentType": 5125, "count": mesh.indices.len(), "type": "SCALAR" },
+        ],
+    });
+
+    // The JSON chunk is padded with spaces, the binary chunk with zeros
+    let mut json = serde_json::to_vec(&document).map_err(io::Error::other)?;
+    while json.len() % 4 != 0 {
+        json.push(b' ');
+    }
+    let length = 12 + 8 + json.len() + 8 + buffer.len();
+    let length = u32::try_from(length).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "mesh is too large for glb"))?;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(b"glTF")?;
+    writer.write_all(&2u32.to_le_bytes())?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(&(json.len() as u32).to_le_bytes())?;
+    writer.write_all(b"JSON")?;
+    writer.write_all(&json)?;
+    writer.write_all(&(buffer.len() as u32).to_le_bytes())?;
+    writer.write_all(b"BIN\0")?;
+    writer.write_all(&buffer)?;
+    writer.flush()
+}
+
+/// Writes the mesh as Wavefront OBJ with a material library next to it, `<name>.mtl`
+pub fn write_obj(mesh: &TerrainMesh, path: &Path) -> io::Result<()> {
+    let mtl_path = path.with_extension("mtl");
+    let mtl_name = mtl_path.file_name().map_or_else(|| "terrain.mtl".into(), |name| name.to_string_lossy());
+
+    let mut mtl = BufWriter::new(File::create(&mtl_path)?);
+    writeln!(mtl, "newmtl terrain")?;
+    writeln!(mtl, "Ka 0.0 0.0 0.0")?;
+    writeln!(mtl, "Kd 1.0 1.0 1.0")?;
+    writeln!(mtl, "Ks 0.0 0.0 0.0")?;
+    writeln!(mtl, "illum 1")?;
+    mtl.flush()?;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "mtllib {}", mtl_name)?;
+    writeln!(writer, "o Terrain")?;
+    for (position, color) in mesh.positions.iter().zip(&mesh.colors) {
+        writeln!(writer, "v {} {} {} {} {} {}", position[0], position[1], position[2], color[0], color[1], color[2])?;
+    }
+    for normal in &mesh.normals {
+        writeln!(writer, "vn {} {} {}", normal[0], normal[1], normal[2])?;
+    }
+    writeln!(writer, "usemtl terrain")?;
+    // OBJ indices start at 1
+    for triangle in mesh.indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0] + 1, triangle[1] + 1, triangle[2] + 1];
+        writeln!(writer, "f {a}//{a} {b}//{b} {c}//{c}")?;
+    }
+    writer.flush()
+}
+
+/// Picks the format from the file extension, `.glb` or `.obj`
+pub fn write_mesh(mesh: &TerrainMesh, path: &Path) -> io::Result<()> {
+    match path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase).as_deref() {
+        Some("glb") => write_glb(mesh, path),
+        Some("obj") => write_obj(mesh, path),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown mesh format {}, expected .glb or .obj", path.display()),
+        )),
+    }
+}
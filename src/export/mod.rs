@@ -0,0 +1,75 @@
+use rayon::prelude::*;
+
+pub mod layers;
+pub mod heightmap;
+pub mod images;
+pub mod geotiff;
+pub mod mesh;
+
+/// Resamples a square plane stored as `x * size + z` to `resolution` x `resolution`.
+/// Shrinking averages every tile that falls into a pixel, growing interpolates between the corner aligned tiles.
+pub fn resample(plane: &[f32], size: usize, resolution: usize) -> Vec<f32> {
+    if resolution == size {
+        return plane.to_vec();
+    }
+
+    // Resampling is separable, first along z inside every row, then along x across the rows
+    let mut rows = vec![0.0; size * resolution];
+    rows.par_chunks_mut(resolution).enumerate().for_each(|(x, row)| {
+        resample_line(|z| plane[x * size + z], size, row);
+    });
+
+    let mut result = vec![0.0; resolution * resolution];
+    let mut columns = vec![0.0; resolution * resolution];
+    columns.par_chunks_mut(resolution).enumerate().for_each(|(z, column)| {
+        resample_line(|x| rows[x * resolution + z], size, column);
+    });
+    for z in 0..resolution {
+        for x in 0..resolution {
+            result[x * resolution + z] = columns[z * resolution + x];
+        }
+    }
+    result
+}
+
+/// Resamples `size` values read through `get` into `out`
+fn resample_line(get: impl Fn(usize) -> f32, size: usize, out: &mut [f32]) {
+    let resolution = out.len();
+    if resolution < size {
+        for (i, value) in out.iter_mut().enumerate() {
+            let start = i * size / resolution;
+            let end = ((i + 1) * size / resolution).max(start + 1);
+            *value = (start..end).map(&get).sum::<f32>() / (end - start) as f32;
+        }
+    } else {
+        let scale = (size - 1) as f32 / (resolution - 1).max(1) as f32;
+        for (i, value) in out.iter_mut().enumerate() {
+            let position = i as f32 * scale;
+            let low = (position as usize).min(size - 1);
+            let high = (low + 1).min(size - 1);
+            let t = position - low as f32;
+            *value = get(low) * (1.0 - t) + get(high) * t;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resamples_planes() {
+        // Tile (x, z) = x * 10 + z
+        let plane: Vec<f32> = (0..4).flat_map(|x| (0..4).map(move |z| (x * 10 + z) as f32)).collect();
+        assert_eq!(resample(&plane, 4, 4), plane);
+
+        // Shrinking averages 2 x 2 blocks
+        assert_eq!(resample(&plane, 4, 2), [5.5, 7.5, 25.5, 27.5]);
+
+        // Growing keeps the corners and interpolates between them
+        let grown = resample(&plane, 4, 7);
+        assert_eq!(grown.len(), 49);
+        assert_eq!([grown[0], grown[6], grown[42], grown[48]], [0.0, 3.0, 30.0, 33.0]);
+        assert_eq!(grown[7 + 1], 5.5);
+    }
+}
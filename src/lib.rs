@@ -0,0 +1,4 @@
+pub mod world_generation;
+pub mod export;
+#[cfg(feature = "render")]
+pub mod utils;
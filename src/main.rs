@@ -1,5 +1,3 @@
-mod world_generation;
-mod utils;
 use bevy::{
     color::palettes::css::*,
     pbr::wireframe::{Wireframe, WireframeConfig, WireframePlugin},
@@ -9,12 +7,38 @@ use bevy::{
 use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
 use bevy::render::mesh::Mesh;
 use std::f32::consts::PI;
-use pyri_tooltip::prelude::*;
-use world_generation::meshing::generate_terrain_mesh;
-use utils::mouse::{update_hovered_tile, HoveredTile, update_tooltip, CameraState};
+use std::path::PathBuf;
+use terrain_generation::export::mesh::{write_glb, write_obj};
+use terrain_generation::world_generation::meshing::{generate_terrain, terrain_chunk_meshes, TerrainMesh};
+use terrain_generation::world_generation::save::load_world;
+use terrain_generation::world_generation::progress::Progress;
+use terrain_generation::world_generation::settings::GenerationSettings;
+use terrain_generation::utils::inspector::{copy_tile_json, pin_tile, spawn_tile_inspector, update_tile_inspector, PinnedTile};
+use terrain_generation::utils::lod::update_terrain_lod;
+use terrain_generation::utils::mouse::{update_hovered_tile, HoveredTile};
+use terrain_generation::utils::overlay::{spawn_overlay_legend, switch_terrain_view, update_overlay_legend, TerrainView};
+use terrain_generation::utils::settings_panel::{
+    adjust_settings, button_colors, press_panel_buttons, spawn_settings_panel, toggle_settings_panel, update_generation_status,
+    update_setting_values,
+};
+use terrain_generation::utils::terrain::{
+    finish_generation, spawn_terrain, start_generation, LastGeneration, RegenerateWorld, Terrain, TerrainAssets,
+};
+use terrain_generation::utils::terrain_material::TerrainMaterial;
+use terrain_generation::utils::water::animate_water;
+
+/// World file given on the command line, started from instead of generating a new world
+#[derive(Resource)]
+struct WorldFile(PathBuf);
 
 fn main() {
-    App::new()
+    let mut app = App::new();
+    app.insert_resource(GenerationSettings::load_or_default());
+    if let Some(path) = std::env::args().nth(1) {
+        app.insert_resource(WorldFile(PathBuf::from(path)));
+    }
+
+    app
         .add_plugins((
             DefaultPlugins.set(RenderPlugin {
                 render_creation: RenderCreation::Automatic(WgpuSettings {
@@ -27,6 +51,7 @@ fn main() {
             // You need to add this plugin to enable wireframe rendering
             WireframePlugin,
             PanOrbitCameraPlugin,
+            MaterialPlugin::<TerrainMaterial>::default(),
         ))
         // Wireframes can be configured with this resource. This can be changed at runtime.
         .insert_resource(WireframeConfig {
@@ -38,17 +63,31 @@ fn main() {
             // Can be changed per mesh using the `WireframeColor` component.
             default_color: WHITE.into(),
         })
-        // Add the tooltip plugin
-        .add_plugins(TooltipPlugin::default())
-        .add_systems(Startup, startup)
+        .add_systems(Startup, (startup, spawn_settings_panel, spawn_tile_inspector, spawn_overlay_legend))
         .add_systems(Update, toggle_wireframe)
+        .init_resource::<TerrainView>()
+        .add_systems(Update, (switch_terrain_view, update_overlay_legend).chain())
+        .add_systems(Update, export_terrain)
+        .add_systems(Update, (update_terrain_lod, animate_water))
+        // Picking and inspecting tiles
         .insert_resource(HoveredTile::default())
-        .add_systems(Update, update_hovered_tile)
-        .add_systems(Update, update_tooltip)
+        .init_resource::<PinnedTile>()
+        .add_systems(Update, (update_hovered_tile, pin_tile, update_tile_inspector, copy_tile_json).chain())
+        // Settings panel and regenerating the world in the background
+        .add_event::<RegenerateWorld>()
+        .init_resource::<LastGeneration>()
+        .add_systems(Update, (toggle_settings_panel, button_colors, adjust_settings, update_setting_values, press_panel_buttons))
+        .add_systems(Update, (start_generation, finish_generation, update_generation_status).chain())
         .run();
 }
 
-fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materials: ResMut<Assets<StandardMaterial>>) {
+fn startup(
+    mut commands: Commands,
+    mut assets: TerrainAssets,
+    view: Res<TerrainView>,
+    world_file: Option<Res<WorldFile>>,
+    settings: Res<GenerationSettings>,
+) {
     // Spawn camera
     commands.spawn((
         Camera3d { ..default() },
@@ -56,26 +95,43 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
         PanOrbitCamera::default(),
     ));
 
-    commands.insert_resource(CameraState::default());
-
-    // how many 4096 chunks we will have
-    let world_chunks: f32 = 4.0;
-    let world_size: f32 = world_chunks*4096.0;
-
+    let progress = Progress::printing();
+    let saved = world_file.and_then(|world_file| {
+        println!("Loading {}...", world_file.0.display());
+        load_world(&world_file.0)
+            .map_err(|err| eprintln!("Could not load {}, generating a new world: {}", world_file.0.display(), err))
+            .ok()
+    });
+    let mut settings = settings.clone();
+    let (chunks, world_map) = match saved {
+        Some(saved) => {
+            println!("WORLD LOADED! (seed {})", saved.seed);
+            // Keep the settings the world was made with, so regenerating it gives the same world
+            match saved.generation_settings() {
+                Ok(saved_settings) => {
+                    settings = saved_settings;
+                    commands.insert_resource(settings.clone());
+                }
+                Err(err) => eprintln!("Keeping the current settings, {}", err),
+            }
+            let chunks = terrain_chunk_meshes(&saved.world_map, &settings.terrain_mesh, &progress)
+                .expect("meshing without a cancellation token is never cancelled");
+            (chunks, saved.world_map)
+        }
+        None => {
+            let generated = generate_terrain(&settings, &progress)
+                .expect("generation without a cancellation token is never cancelled");
+            println!("WORLD GENERATED!");
+            generated
+        }
+    };
 
-    let (mesh, world_map) = generate_terrain_mesh(world_size, 1000, 1);
-    println!("WORLD GENERATED!");
+    // Spawn the terrain chunks
+    spawn_terrain(&mut commands, &mut assets, &world_map, &settings, *view, chunks);
 
     // Insert world_map as resource
     commands.insert_resource(world_map);
 
-    // Spawn mesh
-    commands.spawn((
-        Mesh3d(meshes.add(mesh)),
-        MeshMaterial3d(materials.add(Color::WHITE)),
-        Terrain,
-    ));
-
     // Spawn lighting
     commands.spawn((
         DirectionalLight {
@@ -89,15 +145,8 @@ fn startup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, mut materia
             ..default()
         },
     ));
-
-    // Spawn tooltip UI
-    commands.spawn(Tooltip::cursor("Hover over a tile"));
-    
 }
 
-#[derive(Component)]
-struct Terrain;
-
 /// Quick way to toggle wireframes
 fn toggle_wireframe(
     mut commands: Commands,
@@ -114,3 +163,33 @@ fn toggle_wireframe(
         }
     }
 }
+
+/// Directory the terrain is exported to
+const EXPORT_DIR: &str = "exports";
+
+/// Export the terrain meshes as they are shown to glb and obj
+fn export_terrain(
+    terrain: Query<(&Mesh3d, &GlobalTransform), With<Terrain>>,
+    meshes: Res<Assets<Mesh>>,
+    input: Res<ButtonInput<KeyCode>>,
+) {
+    if !input.just_pressed(KeyCode::KeyE) {
+        return;
+    }
+
+    let mut export = TerrainMesh::default();
+    for (mesh, transform) in &terrain {
+        if let Some(mesh) = meshes.get(&mesh.0).and_then(TerrainMesh::from_mesh) {
+            export.append(&mesh, transform.translation().to_array());
+        }
+    }
+
+    let dir = std::path::Path::new(EXPORT_DIR);
+    let result = std::fs::create_dir_all(dir)
+        .and_then(|_| write_glb(&export, &dir.join("terrain.glb")))
+        .and_then(|_| write_obj(&export, &dir.join("terrain.obj")));
+    match result {
+        Ok(()) => println!("Exported terrain to {}", dir.display()),
+        Err(err) => println!("Could not export terrain: {}", err),
+    }
+}
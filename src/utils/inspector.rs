@@ -0,0 +1,176 @@
+use bevy::prelude::*;
+use serde_json::{json, Value};
+use crate::utils::mouse::HoveredTile;
+use crate::world_generation::meshing::{tile_position, TerrainChunk};
+use crate::world_generation::tile::WorldMap;
+// Panel showing everything known about the hovered tile
+// The rows come from the JSON of the tile, so new layers of `Tile` show up without touching the panel
+// Clicking the terrain pins the tile, Escape unpins it, C copies its JSON to the clipboard
+
+const PANEL_COLOR: Color = Color::srgba(0.08, 0.08, 0.1, 0.85);
+const FONT_SIZE: f32 = 14.0;
+/// Farthest the cursor can move between press and release for a click, further is orbiting the camera
+const CLICK_DISTANCE: f32 = 4.0;
+
+#[derive(Component)]
+pub struct TileInspector;
+
+#[derive(Component)]
+pub struct InspectorText;
+
+/// Tile shown by the inspector instead of the hovered one
+#[derive(Resource, Default)]
+pub struct PinnedTile(pub Option<(usize, usize)>);
+
+/// Everything about a tile as JSON: its local and global coordinates, position in the app, chunk and layers
+pub fn tile_json(world_map: &WorldMap, chunks: &[TerrainChunk], x: usize, z: usize) -> Value {
+    let (global_x, global_z) = world_map.global_coords(x, z);
+    let [position_x, position_z] = tile_position(world_map, x, z);
+    // Every chunk but the last of a row or column is as wide as the chunk size
+    let chunk_size = chunks.iter().map(|chunk| chunk.width.max(chunk.depth)).max().unwrap_or(1).max(1);
+    let chunk = chunks
+        .iter()
+        .filter(|chunk| chunk.contains(x, z))
+        // Tiles on a shared edge go to the chunk they start
+        .max_by_key(|chunk| (chunk.x, chunk.z))
+        .map(|chunk| [chunk.x / chunk_size, chunk.z / chunk_size]);
+    json!({
+        "tile": [x, z],
+        "global": [global_x, global_z],
+        "position": [position_x, world_map.get_height(x, z), position_z],
+        "chunk": chunk,
+        "layers": world_map.tile(x, z),
+    })
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::Number(number) if number.is_f64() => format!("{:.3}", number.as_f64().unwrap_or_default()),
+        Value::String(string) => string.clone(),
+        Value::Array(values) => format!("[{}]", values.iter().map(format_value).collect::<Vec<_>>().join(", ")),
+        value => value.to_string(),
+    }
+}
+
+/// One line per field, the layers are listed after the location
+fn format_tile(json: &Value) -> String {
+    let Value::Object(fields) = json else {
+        return format_value(json);
+    };
+    let mut lines = Vec::new();
+    for (name, value) in fields {
+        match value {
+            Value::Object(layers) => lines.extend(layers.iter().map(|(name, value)| format!("{}: {}", name, format_value(value)))),
+            value => lines.push(format!("{}: {}", name, format_value(value))),
+        }
+    }
+    lines.join("\n")
+}
+
+/// Tile the inspector shows, the pinned one or else the hovered one
+fn inspected_tile(pinned: &PinnedTile, hovered: &HoveredTile) -> Option<(usize, usize)> {
+    pinned.0.or(hovered.0.map(|hit| hit.tile))
+}
+
+pub fn spawn_tile_inspector(mut commands: Commands) {
+    commands
+        .spawn((
+            Node {
+                position_type: PositionType::Absolute,
+                top: Val::Px(10.0),
+                left: Val::Px(10.0),
+                padding: UiRect::all(Val::Px(8.0)),
+                ..default()
+            },
+            BackgroundColor(PANEL_COLOR),
+            TileInspector,
+        ))
+        .with_child((
+            Text::new("Hover over a tile"),
+            TextFont { font_size: FONT_SIZE, ..default() },
+            TextColor(Color::WHITE),
+            InspectorText,
+        ));
+}
+
+/// Pins the hovered tile on click, clicking where there is no terrain or pressing Escape unpins it
+pub fn pin_tile(
+    mouse: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    windows: Query<&Window>,
+    interactions: Query<&Interaction>,
+    hovered: Res<HoveredTile>,
+    world_map: Res<WorldMap>,
+    mut pinned: ResMut<PinnedTile>,
+    mut pressed_at: Local<Option<Vec2>>,
+) {
+    let size = world_map.get_size() as usize;
+    if pinned.0.is_some_and(|(x, z)| x >= size || z >= size) || keys.just_pressed(KeyCode::Escape) {
+        pinned.0 = None;
+    }
+
+    let cursor = windows.get_single().ok().and_then(Window::cursor_position);
+    if mouse.just_pressed(MouseButton::Left) {
+        // Clicks on the UI are not meant for the terrain
+        let over_ui = interactions.iter().any(|interaction| *interaction != Interaction::None);
+        *pressed_at = if over_ui { None } else { cursor };
+    }
+    if mouse.just_released(MouseButton::Left) {
+        let click = pressed_at.take().zip(cursor).is_some_and(|(pressed, released)| pressed.distance(released) <= CLICK_DISTANCE);
+        if click {
+            pinned.0 = hovered.0.map(|hit| hit.tile);
+        }
+    }
+}
+
+pub fn update_tile_inspector(
+    hovered: Res<HoveredTile>,
+    pinned: Res<PinnedTile>,
+    world_map: Res<WorldMap>,
+    chunks: Query<&TerrainChunk>,
+    mut texts: Query<&mut Text, With<InspectorText>>,
+) {
+    if !(hovered.is_changed() || pinned.is_changed() || world_map.is_changed()) {
+        return;
+    }
+    let content = match inspected_tile(&pinned, &hovered) {
+        Some((x, z)) => {
+            let chunks: Vec<TerrainChunk> = chunks.iter().copied().collect();
+            let title = if pinned.0.is_some() { "Pinned tile (Esc to unpin, C to copy)" } else { "Hovered tile (click to pin, C to copy)" };
+            format!("{}\n{}", title, format_tile(&tile_json(&world_map, &chunks, x, z)))
+        }
+        None => "Hover over a tile".to_string(),
+    };
+    for mut text in &mut texts {
+        text.0 = content.clone();
+    }
+}
+
+/// Copies the JSON of the inspected tile to the clipboard, or prints it if there is no clipboard
+pub fn copy_tile_json(
+    keys: Res<ButtonInput<KeyCode>>,
+    hovered: Res<HoveredTile>,
+    pinned: Res<PinnedTile>,
+    world_map: Res<WorldMap>,
+    chunks: Query<&TerrainChunk>,
+    mut clipboard: Local<Option<arboard::Clipboard>>,
+) {
+    if !keys.just_pressed(KeyCode::KeyC) {
+        return;
+    }
+    let Some((x, z)) = inspected_tile(&pinned, &hovered) else {
+        return;
+    };
+    let chunks: Vec<TerrainChunk> = chunks.iter().copied().collect();
+    let json = serde_json::to_string_pretty(&tile_json(&world_map, &chunks, x, z)).unwrap_or_default();
+
+    // The clipboard is kept open, on X11 its content goes away with it
+    if clipboard.is_none() {
+        *clipboard = arboard::Clipboard::new().ok();
+    }
+    match clipboard.as_mut().map(|clipboard| clipboard.set_text(json.clone())) {
+        Some(Ok(())) => println!("Copied tile ({}, {}) to the clipboard", x, z),
+        _ => println!("No clipboard available, tile ({}, {}):\n{}", x, z, json),
+    }
+}
@@ -0,0 +1,93 @@
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy::render::primitives::Aabb;
+use rayon::prelude::*;
+use crate::utils::overlay::TerrainView;
+use crate::utils::water::{set_water_mesh, spawn_water, Water, WaterMaterial};
+use crate::world_generation::meshing::{chunk_mesh, skirt_depth, TerrainChunk};
+use crate::world_generation::settings::GenerationSettings;
+use crate::world_generation::tile::WorldMap;
+use crate::world_generation::water::water_mesh;
+// Level of detail of the terrain chunks
+// Every frame the level of each chunk is picked from its distance to the camera,
+// chunks whose level changed are remeshed nearest first until the frame budget is used up, together with their water
+
+/// Time per frame spent remeshing chunks, the rest waits for the next frames
+const REMESH_BUDGET: Duration = Duration::from_millis(4);
+
+/// Level of detail a terrain chunk is currently meshed at, 0 is full resolution
+#[derive(Component, Debug, Clone, Copy)]
+pub struct TerrainLod(pub u32);
+
+pub fn update_terrain_lod(
+    mut commands: Commands,
+    cameras: Query<&GlobalTransform, With<Camera3d>>,
+    mut chunks: Query<(Entity, &TerrainChunk, &GlobalTransform, &Mesh3d, &mut TerrainLod, &mut Aabb, Option<&Children>), Without<Water>>,
+    mut water: Query<(&Mesh3d, &mut Aabb, &mut Visibility), With<Water>>,
+    water_material: Option<Res<WaterMaterial>>,
+    world_map: Res<WorldMap>,
+    settings: Res<GenerationSettings>,
+    view: Res<TerrainView>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    let Ok(camera) = cameras.get_single() else {
+        return;
+    };
+    let eye = camera.translation();
+    let params = &settings.terrain_mesh;
+
+    // Distance from the camera to the closest point of each chunk's bounding box
+    let mut changes: Vec<(f32, Entity, TerrainChunk, u32)> = chunks
+        .iter()
+        .filter_map(|(entity, chunk, transform, _, lod, aabb, _)| {
+            let center = transform.transform_point(Vec3::from(aabb.center));
+            let distance = ((eye - center).abs() - Vec3::from(aabb.half_extents)).max(Vec3::ZERO).length();
+            let level = params.lod_level(distance, lod.0);
+            (level != lod.0).then_some((distance, entity, *chunk, level))
+        })
+        .collect();
+    changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let started = Instant::now();
+    let coarsest_step = params.lod_step(params.max_lod());
+    for batch in changes.chunks(rayon::current_num_threads().max(1)) {
+        if started.elapsed() > REMESH_BUDGET {
+            break;
+        }
+        let remeshed: Vec<_> = batch
+            .par_iter()
+            .map(|&(_, entity, chunk, level)| {
+                let depth = skirt_depth(&world_map, &chunk, coarsest_step);
+                let step = params.lod_step(level);
+                let mut mesh = chunk_mesh(&world_map, &chunk, step, depth);
+                view.color_mesh(&world_map, &chunk, params.chunk_size, &mut mesh);
+                (entity, chunk, level, mesh, water_mesh(&world_map, &chunk, step, &settings.water))
+            })
+            .collect();
+
+        for (entity, chunk, level, mesh, chunk_water) in remeshed {
+            let Ok((_, _, _, mesh_handle, mut lod, mut aabb, children)) = chunks.get_mut(entity) else {
+                continue;
+            };
+            let (min, max) = mesh.bounds();
+            *aabb = Aabb::from_min_max(Vec3::from(min), Vec3::from(max));
+            meshes.insert(&mesh_handle.0, Mesh::from(mesh));
+            lod.0 = level;
+
+            // Chunks whose water was too small for the coarser levels get it once it shows up
+            let existing = children.and_then(|children| children.iter().find(|&&child| water.contains(child)).copied());
+            match existing {
+                Some(child) => {
+                    let (water_handle, mut water_aabb, mut visibility) = water.get_mut(child).unwrap();
+                    set_water_mesh(&mut meshes, &water_handle.0, &mut water_aabb, &mut visibility, &chunk, chunk_water);
+                }
+                None => {
+                    if let Some(material) = &water_material {
+                        spawn_water(&mut commands, &mut meshes, &material.0, entity, &chunk, chunk_water);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1 +1,8 @@
-pub mod mouse;
\ No newline at end of file
+pub mod inspector;
+pub mod lod;
+pub mod mouse;
+pub mod overlay;
+pub mod settings_panel;
+pub mod terrain;
+pub mod terrain_material;
+pub mod water;
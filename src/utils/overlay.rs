@@ -0,0 +1,317 @@
+use bevy::prelude::*;
+use bevy::render::mesh::VertexAttributeValues;
+use crate::utils::terrain::Terrain;
+use crate::utils::terrain_material::{set_terrain_view, TerrainMaterial};
+use crate::world_generation::biomes::BiomeRegistry;
+use crate::world_generation::meshing::{TerrainChunk, TerrainMesh};
+use crate::world_generation::settings::GenerationSettings;
+use crate::world_generation::tile::{WorldMap, MAX_HEIGHT};
+// Overlays recoloring the terrain with one layer of the world map
+// The number keys pick an overlay and V cycles through them, the legend in the bottom left corner explains the colors.
+// Overlays are the vertex colors of the chunk meshes: switching recolors the meshes on screen,
+// `update_terrain_lod` and `spawn_terrain` color new meshes for the current overlay, the world map is never regenerated
+
+const PANEL_COLOR: Color = Color::srgba(0.08, 0.08, 0.1, 0.85);
+const FONT_SIZE: f32 = 14.0;
+const SWATCH_SIZE: f32 = 14.0;
+/// Colors of the chunks overlay, `chunk_color` never gives neighbouring chunks the same one
+const CHUNK_COLORS: [[f32; 3]; 5] = [[0.8, 0.25, 0.2], [0.2, 0.55, 0.85], [0.9, 0.75, 0.2], [0.3, 0.7, 0.3], [0.6, 0.35, 0.75]];
+/// Flow is shown on a log scale up to this many tiles
+const MAX_FLOW_EXPONENT: f32 = 6.0;
+
+/// What the terrain shows
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TerrainView {
+    /// Biome textures with rock on steep slopes and snow on high ground
+    #[default]
+    Textures,
+    /// Flat colors of the biomes, `Biome::color`
+    Biomes,
+    Height,
+    Temperature,
+    Rainfall,
+    /// Rise over run of the ground
+    Slope,
+    /// Number of tiles draining through a tile
+    Flow,
+    /// Which chunk a tile belongs to
+    Chunks,
+}
+
+impl TerrainView {
+    pub const ALL: [TerrainView; 8] = [
+        TerrainView::Textures,
+        TerrainView::Biomes,
+        TerrainView::Height,
+        TerrainView::Temperature,
+        TerrainView::Rainfall,
+        TerrainView::Slope,
+        TerrainView::Flow,
+        TerrainView::Chunks,
+    ];
+    /// Keys picking the views of `ALL`
+    const KEYS: [KeyCode; 8] = [
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+        KeyCode::Digit4,
+        KeyCode::Digit5,
+        KeyCode::Digit6,
+        KeyCode::Digit7,
+        KeyCode::Digit8,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            TerrainView::Textures => "Textures",
+            TerrainView::Biomes => "Biomes",
+            TerrainView::Height => "Height",
+            TerrainView::Temperature => "Temperature",
+            TerrainView::Rainfall => "Rainfall",
+            TerrainView::Slope => "Slope",
+            TerrainView::Flow => "Flow accumulation",
+            TerrainView::Chunks => "Chunks",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        let index = TerrainView::ALL.iter().position(|&view| view == self).unwrap_or_default();
+        TerrainView::ALL[(index + 1) % TerrainView::ALL.len()]
+    }
+
+    /// Colors of the continuous overlays at increasing values, linear RGB
+    fn stops(self, world_map: &WorldMap) -> Vec<(f32, [f32; 3])> {
+        let sea_level = world_map.sea_level();
+        let land = |share: f32| sea_level + (MAX_HEIGHT - sea_level) * share;
+        match self {
+            TerrainView::Height => vec![
+                (0.0, [0.0, 0.03, 0.25]),
+                (sea_level, [0.2, 0.5, 0.8]),
+                (sea_level, [0.1, 0.45, 0.15]),
+                (land(0.4), [0.85, 0.8, 0.3]),
+                (land(0.75), [0.5, 0.33, 0.2]),
+                (MAX_HEIGHT, [1.0, 1.0, 1.0]),
+            ],
+            TerrainView::Temperature => vec![
+                (-1.0, [0.1, 0.2, 0.8]),
+                (-0.5, [0.3, 0.7, 0.9]),
+                (0.0, [0.95, 0.95, 0.9]),
+                (0.5, [0.95, 0.6, 0.2]),
+                (1.0, [0.7, 0.05, 0.05]),
+            ],
+            TerrainView::Rainfall => vec![
+                (0.0, [0.8, 0.65, 0.4]),
+                (0.33, [0.75, 0.8, 0.35]),
+                (0.66, [0.2, 0.65, 0.3]),
+                (1.0, [0.1, 0.3, 0.75]),
+            ],
+            TerrainView::Slope => vec![
+                (0.0, [0.2, 0.6, 0.2]),
+                (0.5, [0.9, 0.85, 0.2]),
+                (1.0, [0.9, 0.45, 0.1]),
+                (2.0, [0.6, 0.05, 0.05]),
+            ],
+            TerrainView::Flow => vec![
+                (0.0, [0.9, 0.88, 0.8]),
+                (2.0, [0.55, 0.75, 0.9]),
+                (4.0, [0.15, 0.4, 0.85]),
+                (MAX_FLOW_EXPONENT, [0.05, 0.1, 0.45]),
+            ],
+            TerrainView::Textures | TerrainView::Biomes | TerrainView::Chunks => Vec::new(),
+        }
+    }
+
+    /// Value of tile (x, z) the continuous overlays color by
+    fn value(self, world_map: &WorldMap, x: usize, z: usize) -> f32 {
+        let i = world_map.index(x, z);
+        match self {
+            TerrainView::Height => world_map.heights[i],
+            TerrainView::Temperature => world_map.temperature_at(i),
+            TerrainView::Rainfall => world_map.rainfall_at(i),
+            TerrainView::Slope => slope(world_map, x, z),
+            TerrainView::Flow => world_map.flows.as_ref().map_or(0.0, |flows| flows[i].max(1.0).log10()),
+            TerrainView::Textures | TerrainView::Biomes | TerrainView::Chunks => 0.0,
+        }
+    }
+
+    /// Label of a value in the legend
+    fn format_value(self, value: f32) -> String {
+        match self {
+            TerrainView::Height => format!("{:.0}", value),
+            TerrainView::Flow => format!("{:.0} tiles", 10f32.powf(value)),
+            _ => format!("{:.2}", value),
+        }
+    }
+
+    /// Vertex colors for the tiles under `positions`, given relative to the first tile of `chunk` like in `chunk_mesh`
+    pub fn vertex_colors(
+        self,
+        world_map: &WorldMap,
+        chunk: &TerrainChunk,
+        chunk_size: u32,
+        positions: &[[f32; 3]],
+    ) -> Vec<[f32; 4]> {
+        let last = world_map.get_size() as usize - 1;
+        let stops = self.stops(world_map);
+        positions
+            .iter()
+            .map(|&[x, _, z]| {
+                let x = (chunk.x + x.round().max(0.0) as usize).min(last);
+                let z = (chunk.z + z.round().max(0.0) as usize).min(last);
+                match self {
+                    TerrainView::Textures | TerrainView::Biomes => {
+                        world_map.get_biome(x, z).map_or([1.0; 4], |biome| biome.color())
+                    }
+                    TerrainView::Chunks => chunk_color(chunk, chunk_size),
+                    _ => ramp(&stops, self.value(world_map, x, z)),
+                }
+            })
+            .collect()
+    }
+
+    /// Colors a freshly meshed chunk, the meshes come with the biome colors
+    pub fn color_mesh(self, world_map: &WorldMap, chunk: &TerrainChunk, chunk_size: u32, mesh: &mut TerrainMesh) {
+        if !matches!(self, TerrainView::Textures | TerrainView::Biomes) {
+            mesh.colors = self.vertex_colors(world_map, chunk, chunk_size, &mesh.positions);
+        }
+    }
+
+    /// Colors and labels explaining the overlay
+    fn legend(self, world_map: &WorldMap) -> Vec<(Color, String)> {
+        let color = |[r, g, b]: [f32; 3]| Color::linear_rgb(r, g, b);
+        match self {
+            TerrainView::Textures => vec![(Color::WHITE, "Biome textures, rock on steep slopes, snow up high".to_string())],
+            TerrainView::Biomes => BiomeRegistry::global()
+                .iter()
+                .map(|(biome, def)| {
+                    let [r, g, b, _] = biome.color();
+                    (color([r, g, b]), def.name.clone())
+                })
+                .collect(),
+            TerrainView::Chunks => vec![(color(CHUNK_COLORS[0]), "Neighbouring chunks differ in color".to_string())],
+            TerrainView::Flow if world_map.flows.is_none() => {
+                vec![(Color::WHITE, "Flow was not kept, set keep_flow in the hydrology settings".to_string())]
+            }
+            _ => self
+                .stops(world_map)
+                .into_iter()
+                .rev()
+                .map(|(value, rgb)| (color(rgb), self.format_value(value)))
+                .collect(),
+        }
+    }
+}
+
+/// Rise over run at a tile, from the heights of its neighbours
+fn slope(world_map: &WorldMap, x: usize, z: usize) -> f32 {
+    let last = world_map.get_size() as usize - 1;
+    let (left, right) = (x.saturating_sub(1), (x + 1).min(last));
+    let (up, down) = (z.saturating_sub(1), (z + 1).min(last));
+    let dx = (world_map.get_height(right, z) - world_map.get_height(left, z)) / (right - left).max(1) as f32;
+    let dz = (world_map.get_height(x, down) - world_map.get_height(x, up)) / (down - up).max(1) as f32;
+    (dx * dx + dz * dz).sqrt()
+}
+
+/// Color of `value` between the stops around it, stops at the same value make a hard edge
+fn ramp(stops: &[(f32, [f32; 3])], value: f32) -> [f32; 4] {
+    let upper = stops.iter().position(|&(stop, _)| stop > value).unwrap_or(stops.len());
+    let [r, g, b] = match upper {
+        0 => stops[0].1,
+        upper if upper == stops.len() => stops[upper - 1].1,
+        upper => {
+            let ((start, from), (end, to)) = (stops[upper - 1], stops[upper]);
+            let t = (value - start) / (end - start);
+            [0, 1, 2].map(|channel| from[channel] + (to[channel] - from[channel]) * t)
+        }
+    };
+    [r, g, b, 1.0]
+}
+
+/// Neighbours, including the diagonal ones, always differ in color
+fn chunk_color(chunk: &TerrainChunk, chunk_size: u32) -> [f32; 4] {
+    let chunk_size = chunk_size.max(1) as usize;
+    let index = (chunk.x / chunk_size + 2 * (chunk.z / chunk_size)) % CHUNK_COLORS.len();
+    let [r, g, b] = CHUNK_COLORS[index];
+    [r, g, b, 1.0]
+}
+
+#[derive(Component)]
+pub struct OverlayLegend;
+
+pub fn spawn_overlay_legend(mut commands: Commands) {
+    commands.spawn((
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(10.0),
+            left: Val::Px(10.0),
+            flex_direction: FlexDirection::Column,
+            padding: UiRect::all(Val::Px(8.0)),
+            row_gap: Val::Px(4.0),
+            ..default()
+        },
+        BackgroundColor(PANEL_COLOR),
+        OverlayLegend,
+    ));
+}
+
+/// Rebuilds the rows of the legend when the view or the world map changes
+pub fn update_overlay_legend(
+    mut commands: Commands,
+    view: Res<TerrainView>,
+    world_map: Res<WorldMap>,
+    legends: Query<Entity, With<OverlayLegend>>,
+) {
+    if !(view.is_changed() || world_map.is_changed()) {
+        return;
+    }
+    let text = |value: String| (Text::new(value), TextFont { font_size: FONT_SIZE, ..default() }, TextColor(Color::WHITE));
+    let index = TerrainView::ALL.iter().position(|&other| other == *view).unwrap_or_default();
+    let legend = view.legend(&world_map);
+    for entity in &legends {
+        commands.entity(entity).despawn_descendants().with_children(|panel| {
+            panel.spawn(text(format!("{} ({} of 1-{}, V for the next)", view.label(), index + 1, TerrainView::ALL.len())));
+            for (color, label) in &legend {
+                panel
+                    .spawn(Node { align_items: AlignItems::Center, column_gap: Val::Px(6.0), ..default() })
+                    .with_children(|row| {
+                        row.spawn((Node { width: Val::Px(SWATCH_SIZE), height: Val::Px(SWATCH_SIZE), ..default() }, BackgroundColor(*color)));
+                        row.spawn(text(label.clone()));
+                    });
+            }
+        });
+    }
+}
+
+/// Picks the view with the number keys or the next one with V, then recolors the terrain
+pub fn switch_terrain_view(
+    input: Res<ButtonInput<KeyCode>>,
+    mut view: ResMut<TerrainView>,
+    world_map: Res<WorldMap>,
+    settings: Res<GenerationSettings>,
+    terrain: Query<(&TerrainChunk, &Mesh3d, &MeshMaterial3d<TerrainMaterial>), With<Terrain>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<TerrainMaterial>>,
+) {
+    let picked = TerrainView::KEYS.iter().position(|&key| input.just_pressed(key)).map(|index| TerrainView::ALL[index]);
+    let Some(next) = picked.or_else(|| input.just_pressed(KeyCode::KeyV).then(|| view.next())) else {
+        return;
+    };
+    if next == *view {
+        return;
+    }
+    *view = next;
+
+    for (chunk, mesh, material) in &terrain {
+        if let Some(material) = materials.get_mut(&material.0) {
+            set_terrain_view(material, next);
+        }
+        let Some(mesh) = meshes.get_mut(&mesh.0) else {
+            continue;
+        };
+        let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {
+            continue;
+        };
+        let colors = next.vertex_colors(&world_map, chunk, settings.terrain_mesh.chunk_size, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    }
+}
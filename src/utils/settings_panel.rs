@@ -0,0 +1,328 @@
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bevy::prelude::*;
+use crate::utils::terrain::{GenerationTask, LastGeneration, RegenerateWorld};
+use crate::world_generation::settings::{GenerationSettings, SettingsError};
+use crate::world_generation::tile::MAX_HEIGHT;
+// Panel for editing the generation settings in the app
+// Every setting has a - and + button, holding Shift changes it in steps of 10
+// Tab shows and hides the panel
+
+/// Directory "Save preset" writes to, resources/settings.ron is only ever read
+const PRESET_DIR: &str = "presets";
+
+/// A setting the panel can edit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingField {
+    Seed,
+    SeaLevel,
+    Octaves,
+    HillsFrequency,
+    RidgesFrequency,
+    MountainsFrequency,
+    EquatorTemperature,
+    PoleTemperature,
+    TemperatureNoise,
+    WindDirection,
+    InitialMoisture,
+    OrographicRate,
+    RainfallScale,
+}
+
+impl SettingField {
+    pub const ALL: [SettingField; 13] = [
+        SettingField::Seed,
+        SettingField::SeaLevel,
+        SettingField::Octaves,
+        SettingField::HillsFrequency,
+        SettingField::RidgesFrequency,
+        SettingField::MountainsFrequency,
+        SettingField::EquatorTemperature,
+        SettingField::PoleTemperature,
+        SettingField::TemperatureNoise,
+        SettingField::WindDirection,
+        SettingField::InitialMoisture,
+        SettingField::OrographicRate,
+        SettingField::RainfallScale,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SettingField::Seed => "Seed",
+            SettingField::SeaLevel => "Sea level",
+            SettingField::Octaves => "Noise octaves",
+            SettingField::HillsFrequency => "Hills frequency",
+            SettingField::RidgesFrequency => "Ridges frequency",
+            SettingField::MountainsFrequency => "Mountains frequency",
+            SettingField::EquatorTemperature => "Equator temperature",
+            SettingField::PoleTemperature => "Pole temperature",
+            SettingField::TemperatureNoise => "Temperature noise",
+            SettingField::WindDirection => "Wind direction",
+            SettingField::InitialMoisture => "Initial moisture",
+            SettingField::OrographicRate => "Orographic rain",
+            SettingField::RainfallScale => "Rainfall scale",
+        }
+    }
+
+    pub fn value(self, settings: &GenerationSettings) -> String {
+        match self {
+            SettingField::Seed => settings.seed.to_string(),
+            SettingField::SeaLevel => format!("{:.0}", settings.sea_level),
+            SettingField::Octaves => settings.relief.octaves.to_string(),
+            SettingField::HillsFrequency => format!("{:.1}", settings.relief.hills_frequency),
+            SettingField::RidgesFrequency => format!("{:.1}", settings.relief.ridges_frequency),
+            SettingField::MountainsFrequency => format!("{:.1}", settings.relief.mountains_frequency),
+            SettingField::EquatorTemperature => format!("{:.2}", settings.temperature.equator_temperature),
+            SettingField::PoleTemperature => format!("{:.2}", settings.temperature.pole_temperature),
+            SettingField::TemperatureNoise => format!("{:.2}", settings.temperature.noise_amplitude),
+            SettingField::WindDirection => format!("{:.0}°", settings.rainfall.wind_direction),
+            SettingField::InitialMoisture => format!("{:.2}", settings.rainfall.initial_moisture),
+            SettingField::OrographicRate => format!("{:.2}", settings.rainfall.orographic_rate),
+            SettingField::RainfallScale => format!("{:.2}", settings.rainfall.rainfall_scale),
+        }
+    }
+
+    /// Changes the setting by `steps` steps, keeping it in the range the stages work with
+    pub fn adjust(self, settings: &mut GenerationSettings, steps: i32) {
+        let steps_f32 = steps as f32;
+        let steps_f64 = steps as f64;
+        let relief = &mut settings.relief;
+        let temperature = &mut settings.temperature;
+        let rainfall = &mut settings.rainfall;
+        match self {
+            SettingField::Seed => settings.seed = settings.seed.wrapping_add_signed(steps),
+            SettingField::SeaLevel => settings.sea_level = (settings.sea_level + steps_f32).clamp(1.0, MAX_HEIGHT - 1.0),
+            SettingField::Octaves => relief.octaves = (relief.octaves as i32 + steps).clamp(1, 16) as usize,
+            SettingField::HillsFrequency => relief.hills_frequency = (relief.hills_frequency + steps_f64).max(1.0),
+            SettingField::RidgesFrequency => relief.ridges_frequency = (relief.ridges_frequency + steps_f64).max(1.0),
+            SettingField::MountainsFrequency => relief.mountains_frequency = (relief.mountains_frequency + 0.5 * steps_f64).max(0.5),
+            SettingField::EquatorTemperature => {
+                temperature.equator_temperature = (temperature.equator_temperature + 0.05 * steps_f32).clamp(-1.0, 1.0)
+            }
+            SettingField::PoleTemperature => {
+                temperature.pole_temperature = (temperature.pole_temperature + 0.05 * steps_f32).clamp(-1.0, 1.0)
+            }
+            SettingField::TemperatureNoise => {
+                temperature.noise_amplitude = (temperature.noise_amplitude + 0.05 * steps_f32).clamp(0.0, 1.0)
+            }
+            SettingField::WindDirection => rainfall.wind_direction = (rainfall.wind_direction + 15.0 * steps_f32).rem_euclid(360.0),
+            SettingField::InitialMoisture => rainfall.initial_moisture = (rainfall.initial_moisture + 0.05 * steps_f32).clamp(0.0, 1.0),
+            SettingField::OrographicRate => rainfall.orographic_rate = (rainfall.orographic_rate + 0.01 * steps_f32).clamp(0.0, 1.0),
+            SettingField::RainfallScale => rainfall.rainfall_scale = (rainfall.rainfall_scale + 0.05 * steps_f32).clamp(0.0, 2.0),
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct SettingsPanel;
+
+/// Button changing a setting by one step in `direction`
+#[derive(Component)]
+pub struct SettingButton {
+    field: SettingField,
+    direction: i32,
+}
+
+/// Text showing the current value of a setting
+#[derive(Component)]
+pub struct SettingValue(SettingField);
+
+#[derive(Component)]
+pub struct RegenerateButton;
+
+#[derive(Component)]
+pub struct CancelButton;
+
+#[derive(Component)]
+pub struct SavePresetButton;
+
+#[derive(Component)]
+pub struct GenerationStatus;
+
+/// Bar filling up with the progress of the running stage
+#[derive(Component)]
+pub struct ProgressBar;
+
+const PANEL_COLOR: Color = Color::srgba(0.08, 0.08, 0.1, 0.85);
+const BUTTON_COLOR: Color = Color::srgb(0.22, 0.22, 0.26);
+const BUTTON_HOVERED_COLOR: Color = Color::srgb(0.32, 0.32, 0.38);
+const BUTTON_PRESSED_COLOR: Color = Color::srgb(0.42, 0.42, 0.5);
+const FONT_SIZE: f32 = 14.0;
+
+fn text(value: impl Into<String>) -> impl Bundle {
+    (Text::new(value), TextFont { font_size: FONT_SIZE, ..default() }, TextColor(Color::WHITE))
+}
+
+fn button(width: Val) -> impl Bundle {
+    (
+        Button,
+        Node {
+            width,
+            height: Val::Px(22.0),
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..default()
+        },
+        BackgroundColor(BUTTON_COLOR),
+    )
+}
+
+pub fn spawn_settings_panel(mut commands: Commands, settings: Res<GenerationSettings>) {
+    commands
+        .spawn((
+            Node {
+                position_type: PositionType::Absolute,
+                top: Val::Px(10.0),
+                right: Val::Px(10.0),
+                flex_direction: FlexDirection::Column,
+                padding: UiRect::all(Val::Px(8.0)),
+                row_gap: Val::Px(4.0),
+                ..default()
+            },
+            BackgroundColor(PANEL_COLOR),
+            SettingsPanel,
+        ))
+        .with_children(|panel| {
+            for field in SettingField::ALL {
+                panel
+                    .spawn(Node {
+                        align_items: AlignItems::Center,
+                        column_gap: Val::Px(4.0),
+                        ..default()
+                    })
+                    .with_children(|row| {
+                        row.spawn((text(field.label()), Node { width: Val::Px(150.0), ..default() }));
+                        row.spawn((button(Val::Px(22.0)), SettingButton { field, direction: -1 }))
+                            .with_child(text("-"));
+                        row.spawn((
+                            text(field.value(&settings)),
+                            Node { width: Val::Px(90.0), justify_content: JustifyContent::Center, ..default() },
+                            TextLayout::new_with_justify(JustifyText::Center),
+                            SettingValue(field),
+                        ));
+                        row.spawn((button(Val::Px(22.0)), SettingButton { field, direction: 1 }))
+                            .with_child(text("+"));
+                    });
+            }
+
+            panel
+                .spawn(Node { column_gap: Val::Px(4.0), margin: UiRect::top(Val::Px(4.0)), ..default() })
+                .with_children(|row| {
+                    row.spawn((button(Val::Percent(34.0)), RegenerateButton)).with_child(text("Regenerate"));
+                    row.spawn((button(Val::Percent(33.0)), CancelButton)).with_child(text("Cancel"));
+                    row.spawn((button(Val::Percent(33.0)), SavePresetButton)).with_child(text("Save preset"));
+                });
+
+            panel.spawn((text(""), GenerationStatus));
+            panel
+                .spawn((
+                    Node { width: Val::Percent(100.0), height: Val::Px(6.0), ..default() },
+                    BackgroundColor(BUTTON_COLOR),
+                ))
+                .with_child((
+                    Node { width: Val::Percent(0.0), height: Val::Percent(100.0), ..default() },
+                    BackgroundColor(Color::srgb(0.4, 0.7, 1.0)),
+                    ProgressBar,
+                ));
+        });
+}
+
+pub fn toggle_settings_panel(input: Res<ButtonInput<KeyCode>>, mut panels: Query<&mut Visibility, With<SettingsPanel>>) {
+    if input.just_pressed(KeyCode::Tab) {
+        for mut visibility in &mut panels {
+            visibility.toggle_visible_hidden();
+        }
+    }
+}
+
+pub fn button_colors(mut buttons: Query<(&Interaction, &mut BackgroundColor), Changed<Interaction>>) {
+    for (interaction, mut color) in &mut buttons {
+        color.0 = match interaction {
+            Interaction::Pressed => BUTTON_PRESSED_COLOR,
+            Interaction::Hovered => BUTTON_HOVERED_COLOR,
+            Interaction::None => BUTTON_COLOR,
+        };
+    }
+}
+
+pub fn adjust_settings(
+    buttons: Query<(&Interaction, &SettingButton), Changed<Interaction>>,
+    input: Res<ButtonInput<KeyCode>>,
+    mut settings: ResMut<GenerationSettings>,
+) {
+    let step = if input.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) { 10 } else { 1 };
+    for (interaction, button) in &buttons {
+        if *interaction == Interaction::Pressed {
+            button.field.adjust(&mut settings, button.direction * step);
+        }
+    }
+}
+
+pub fn update_setting_values(settings: Res<GenerationSettings>, mut values: Query<(&SettingValue, &mut Text)>) {
+    if !settings.is_changed() {
+        return;
+    }
+    for (SettingValue(field), mut text) in &mut values {
+        text.0 = field.value(&settings);
+    }
+}
+
+pub fn press_panel_buttons(
+    regenerate: Query<&Interaction, (Changed<Interaction>, With<RegenerateButton>)>,
+    cancel: Query<&Interaction, (Changed<Interaction>, With<CancelButton>)>,
+    save: Query<&Interaction, (Changed<Interaction>, With<SavePresetButton>)>,
+    settings: Res<GenerationSettings>,
+    generation: Option<Res<GenerationTask>>,
+    mut requests: EventWriter<RegenerateWorld>,
+) {
+    if regenerate.iter().any(|interaction| *interaction == Interaction::Pressed) {
+        requests.send(RegenerateWorld);
+    }
+    if cancel.iter().any(|interaction| *interaction == Interaction::Pressed) {
+        if let Some(generation) = &generation {
+            generation.cancel();
+        }
+    }
+    if save.iter().any(|interaction| *interaction == Interaction::Pressed) {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        let path = Path::new(PRESET_DIR).join(format!("seed-{}-{}.ron", settings.seed, seconds));
+        let result = fs::create_dir_all(PRESET_DIR).map_err(SettingsError::from).and_then(|_| settings.save(&path));
+        match result {
+            Ok(()) => println!("Saved settings to {}", path.display()),
+            Err(err) => println!("Could not save settings: {}", err),
+        }
+    }
+}
+
+pub fn update_generation_status(
+    generation: Option<Res<GenerationTask>>,
+    last_generation: Res<LastGeneration>,
+    mut status: Query<&mut Text, With<GenerationStatus>>,
+    mut bar: Query<&mut Node, With<ProgressBar>>,
+) {
+    let progress = generation.as_ref().and_then(|generation| generation.progress());
+    let message = match (&generation, progress, last_generation.0) {
+        (Some(generation), _, _) if generation.is_cancelled() => "Cancelling...".to_string(),
+        (Some(generation), Some(update), _) => format!(
+            "{} {:.0}% ({:.1}s)",
+            update.stage,
+            update.fraction * 100.0,
+            generation.elapsed().as_secs_f32()
+        ),
+        (Some(_), None, _) => "Starting...".to_string(),
+        (None, _, Some(Ok(duration))) => format!("Generated in {:.1}s", duration.as_secs_f32()),
+        (None, _, Some(Err(_))) => "Generation cancelled".to_string(),
+        (None, _, None) => String::new(),
+    };
+    for mut text in &mut status {
+        if text.0 != message {
+            text.0 = message.clone();
+        }
+    }
+
+    let fraction = progress.map_or(0.0, |update| update.fraction);
+    for mut node in &mut bar {
+        node.width = Val::Percent(fraction * 100.0);
+    }
+}
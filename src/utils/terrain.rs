@@ -0,0 +1,185 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bevy::ecs::system::SystemParam;
+use bevy::pbr::wireframe::Wireframe;
+use bevy::prelude::*;
+use bevy::render::primitives::Aabb;
+use bevy::tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task};
+use crate::utils::lod::TerrainLod;
+use crate::utils::overlay::TerrainView;
+use crate::utils::terrain_material::{terrain_material, terrain_textures, TerrainMaterial};
+use crate::utils::water::{spawn_water, water_material, WaterMaterial};
+use crate::world_generation::meshing::{generate_terrain, tile_position, TerrainChunk, TerrainMesh};
+use crate::world_generation::progress::{CancellationToken, Cancelled, Progress, ProgressUpdate};
+use crate::world_generation::settings::GenerationSettings;
+use crate::world_generation::tile::WorldMap;
+use crate::world_generation::water::water_mesh;
+// The terrain entities and regenerating them without restarting the app
+// Every chunk of the terrain is its own entity with its own bounding box, so chunks out of view are culled
+// Generation runs on the async compute pool, the old terrain stays on screen until the new one is ready
+
+/// Marks the entities showing the terrain, every one also has a `TerrainChunk` and a `TerrainLod`
+#[derive(Component)]
+pub struct Terrain;
+
+/// Chunk meshes and the world map they were made from
+pub type GeneratedTerrain = (Vec<(TerrainChunk, TerrainMesh)>, WorldMap);
+
+/// Asset stores the terrain is spawned into
+#[derive(SystemParam)]
+pub struct TerrainAssets<'w> {
+    pub meshes: ResMut<'w, Assets<Mesh>>,
+    pub materials: ResMut<'w, Assets<StandardMaterial>>,
+    pub terrain_materials: ResMut<'w, Assets<TerrainMaterial>>,
+    pub images: ResMut<'w, Assets<Image>>,
+}
+
+/// Asks for a new world from the current `GenerationSettings`, ignored while a generation is running
+#[derive(Event, Default)]
+pub struct RegenerateWorld;
+
+/// Generation running in the background
+#[derive(Resource)]
+pub struct GenerationTask {
+    /// The terrain together with the settings it was generated with, which may have changed in the panel since
+    task: Task<Result<(GeneratedTerrain, GenerationSettings), Cancelled>>,
+    started: Instant,
+    /// Latest update of the pipeline, written from the task
+    progress: Arc<Mutex<Option<ProgressUpdate>>>,
+    cancellation: CancellationToken,
+}
+
+impl GenerationTask {
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn progress(&self) -> Option<ProgressUpdate> {
+        *self.progress.lock().unwrap()
+    }
+
+    /// Stops the generation at the next check of its stage, the current terrain is kept
+    pub fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+}
+
+/// How long the last regeneration took, or that it was cancelled
+#[derive(Resource, Default)]
+pub struct LastGeneration(pub Option<Result<Duration, Cancelled>>);
+
+/// Spawns an entity per chunk, placed at the position of its first tile, with the chunk's water as a child.
+/// The chunks are meshed at the coarsest level of detail, `update_terrain_lod` refines the ones near the camera.
+pub fn spawn_terrain(
+    commands: &mut Commands,
+    assets: &mut TerrainAssets,
+    world_map: &WorldMap,
+    settings: &GenerationSettings,
+    view: TerrainView,
+    chunks: Vec<(TerrainChunk, TerrainMesh)>,
+) -> Vec<Entity> {
+    let textures = terrain_textures(&mut assets.images);
+    let water = water_material(&mut assets.materials, &mut assets.images, &settings.water);
+    commands.insert_resource(WaterMaterial(water.clone()));
+    let lod = TerrainLod(settings.terrain_mesh.max_lod());
+    chunks
+        .into_iter()
+        .map(|(chunk, mut mesh)| {
+            view.color_mesh(world_map, &chunk, settings.terrain_mesh.chunk_size, &mut mesh);
+            let (min, max) = mesh.bounds();
+            let [x, z] = tile_position(world_map, chunk.x, chunk.z);
+            let chunk_water = water_mesh(world_map, &chunk, settings.terrain_mesh.lod_step(lod.0), &settings.water);
+            let material = terrain_material(world_map, &chunk, &textures, &settings.texturing, view, &mut assets.images);
+            let entity = commands
+                .spawn((
+                    Mesh3d(assets.meshes.add(Mesh::from(mesh))),
+                    MeshMaterial3d(assets.terrain_materials.add(material)),
+                    Transform::from_xyz(x, 0.0, z),
+                    Aabb::from_min_max(Vec3::from(min), Vec3::from(max)),
+                    chunk,
+                    lod,
+                    Terrain,
+                ))
+                .id();
+            spawn_water(commands, &mut assets.meshes, &water, entity, &chunk, chunk_water);
+            entity
+        })
+        .collect()
+}
+
+pub fn start_generation(
+    mut commands: Commands,
+    mut requests: EventReader<RegenerateWorld>,
+    settings: Res<GenerationSettings>,
+    running: Option<Res<GenerationTask>>,
+) {
+    if requests.read().count() == 0 || running.is_some() {
+        return;
+    }
+
+    let latest = Arc::new(Mutex::new(None));
+    let cancellation = CancellationToken::new();
+    let progress = {
+        let latest = latest.clone();
+        Progress::new(move |update| {
+            if update.fraction == 0.0 {
+                println!("{}...", update.stage);
+            }
+            *latest.lock().unwrap() = Some(*update);
+        })
+        .with_cancellation(cancellation.clone())
+    };
+
+    let settings = settings.clone();
+    let task = AsyncComputeTaskPool::get().spawn(async move {
+        let generated = generate_terrain(&settings, &progress)?;
+        Ok((generated, settings))
+    });
+    commands.insert_resource(GenerationTask { task, started: Instant::now(), progress: latest, cancellation });
+}
+
+/// Swaps in the new world map and terrain once the background generation is done
+pub fn finish_generation(
+    mut commands: Commands,
+    generation: Option<ResMut<GenerationTask>>,
+    terrain: Query<(Entity, Has<Wireframe>), With<Terrain>>,
+    mut assets: TerrainAssets,
+    mut last_generation: ResMut<LastGeneration>,
+    view: Res<TerrainView>,
+) {
+    let Some(mut generation) = generation else {
+        return;
+    };
+    let Some(result) = block_on(future::poll_once(&mut generation.task)) else {
+        return;
+    };
+    commands.remove_resource::<GenerationTask>();
+    let ((chunks, world_map), settings) = match result {
+        Ok(generated) => generated,
+        Err(Cancelled) => {
+            println!("Generation cancelled after {:.1?}", generation.elapsed());
+            last_generation.0 = Some(Err(Cancelled));
+            return;
+        }
+    };
+    last_generation.0 = Some(Ok(generation.elapsed()));
+    println!("WORLD REGENERATED in {:.1?}", generation.elapsed());
+
+    // Keep the wireframe toggle of the old terrain
+    let mut wireframe = false;
+    for (entity, has_wireframe) in &terrain {
+        wireframe |= has_wireframe;
+        commands.entity(entity).despawn_recursive();
+    }
+    for entity in spawn_terrain(&mut commands, &mut assets, &world_map, &settings, *view, chunks) {
+        if wireframe {
+            commands.entity(entity).insert(Wireframe);
+        }
+    }
+    commands.insert_resource(world_map);
+}
@@ -0,0 +1,122 @@
+use std::path::Path;
+
+use bevy::image::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor};
+use bevy::pbr::{ExtendedMaterial, MaterialExtension};
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{
+    AsBindGroup, Extent3d, ShaderRef, ShaderType, TextureDimension, TextureFormat, TextureViewDescriptor, TextureViewDimension,
+};
+use crate::utils::overlay::TerrainView;
+use crate::world_generation::biomes::BiomeRegistry;
+use crate::world_generation::meshing::{tile_position, TerrainChunk};
+use crate::world_generation::texturing::{mip_levels, rock_layer, steepness, texture_layers, TexturingParams, TEXTURE_DIR, TEXTURE_TEXELS};
+use crate::world_generation::tile::WorldMap;
+// Material of the terrain chunks, blending a tiling texture per biome instead of the vertex colors
+// Every chunk has a biome map with the biome of each of its tiles, the shader weighs the textures
+// of the four tiles around a pixel by its distance to them, so biome borders stay as sharp as the tiles
+// The vertex colors are still in the meshes and are shown instead for the overlays of `TerrainView`
+
+/// Shader of the terrain, relative to the assets directory
+const SHADER_PATH: &str = "shaders/terrain.wgsl";
+
+pub type TerrainMaterial = ExtendedMaterial<StandardMaterial, TerrainExtension>;
+
+/// Uniform of the terrain shader, the order of the fields matches `TerrainSettings` in the shader
+#[derive(Debug, Clone, Copy, ShaderType, Reflect)]
+pub struct TerrainUniform {
+    /// Position of the first tile of the chunk in the app
+    pub origin: Vec2,
+    pub texture_size: f32,
+    pub rock_layer: u32,
+    /// Steepness at which rock starts to show and covers the ground, see `texturing::steepness`
+    pub rock_steepness: Vec2,
+    pub snow_height: Vec2,
+    /// Steepness above which snow does not stick
+    pub snow_steepness: f32,
+    /// `TerrainView` as a number, 0 shows the textures
+    pub view: u32,
+}
+
+/// Textures and biome map the terrain shader adds to `StandardMaterial`
+#[derive(Asset, AsBindGroup, Reflect, Debug, Clone)]
+pub struct TerrainExtension {
+    #[uniform(100)]
+    pub uniform: TerrainUniform,
+    /// Layer per biome followed by rock and snow, shared by all chunks
+    #[texture(101, dimension = "2d_array")]
+    #[sampler(102)]
+    pub textures: Handle<Image>,
+    /// Biome ID of every tile of the chunk, a row per x
+    #[texture(103, sample_type = "u_int")]
+    pub biomes: Handle<Image>,
+}
+
+impl MaterialExtension for TerrainExtension {
+    fn fragment_shader() -> ShaderRef {
+        SHADER_PATH.into()
+    }
+}
+
+/// Texture array of the biomes, rock and snow with all of their mip levels, see `texture_layers`
+pub fn terrain_textures(images: &mut Assets<Image>) -> Handle<Image> {
+    let registry = BiomeRegistry::global();
+    let layers = rock_layer(registry) + 2;
+    let size = Extent3d { width: TEXTURE_TEXELS, height: TEXTURE_TEXELS, depth_or_array_layers: layers };
+    let mut image = Image::new_fill(size, TextureDimension::D2, &[255; 4], TextureFormat::Rgba8UnormSrgb, RenderAssetUsages::RENDER_WORLD);
+    image.data = texture_layers(registry, Path::new(TEXTURE_DIR));
+    image.texture_descriptor.mip_level_count = mip_levels();
+    image.texture_view_descriptor = Some(TextureViewDescriptor { dimension: Some(TextureViewDimension::D2Array), ..default() });
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::Repeat,
+        address_mode_v: ImageAddressMode::Repeat,
+        anisotropy_clamp: 16,
+        ..ImageSamplerDescriptor::linear()
+    });
+    images.add(image)
+}
+
+/// Biome IDs of the tiles of a chunk, including the tiles of the edges it shares with its neighbours
+fn biome_map(world_map: &WorldMap, chunk: &TerrainChunk) -> Image {
+    // A row of constant x is contiguous in the world map, so every row of the image is a slice of it
+    let data: Vec<u8> = (chunk.x..=chunk.x + chunk.width)
+        .flat_map(|x| &world_map.biomes[world_map.index(x, chunk.z)..=world_map.index(x, chunk.z + chunk.depth)])
+        .copied()
+        .collect();
+    Image::new(
+        Extent3d { width: chunk.depth as u32 + 1, height: chunk.width as u32 + 1, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        data,
+        TextureFormat::R8Uint,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+}
+
+/// Material of a chunk, `textures` comes from `terrain_textures`
+pub fn terrain_material(
+    world_map: &WorldMap,
+    chunk: &TerrainChunk,
+    textures: &Handle<Image>,
+    params: &TexturingParams,
+    view: TerrainView,
+    images: &mut Assets<Image>,
+) -> TerrainMaterial {
+    let uniform = TerrainUniform {
+        origin: Vec2::from(tile_position(world_map, chunk.x, chunk.z)),
+        texture_size: params.texture_size,
+        rock_layer: rock_layer(BiomeRegistry::global()),
+        rock_steepness: Vec2::new(steepness(params.rock_slope[0]), steepness(params.rock_slope[1])),
+        snow_height: Vec2::from(params.snow_height),
+        snow_steepness: steepness(params.snow_max_slope),
+        view: view as u32,
+    };
+    ExtendedMaterial {
+        base: Color::WHITE.into(),
+        extension: TerrainExtension { uniform, textures: textures.clone(), biomes: images.add(biome_map(world_map, chunk)) },
+    }
+}
+
+/// Shows the biome textures or, for every other view, the vertex colors
+pub fn set_terrain_view(material: &mut TerrainMaterial, view: TerrainView) {
+    material.extension.uniform.view = view as u32;
+}
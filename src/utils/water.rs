@@ -0,0 +1,147 @@
+use std::f32::consts::TAU;
+
+use bevy::image::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor};
+use bevy::math::Affine2;
+use bevy::prelude::*;
+use bevy::render::mesh::VertexAttributeValues;
+use bevy::render::primitives::Aabb;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use crate::world_generation::meshing::{TerrainChunk, TerrainMesh};
+use crate::world_generation::water::WaterParams;
+// Translucent water over the terrain
+// Every terrain chunk with water gets a water child, meshed and remeshed at the same level of detail as the chunk
+// The waves are a tiling normal map scrolled over the water, the colors come from the vertices
+
+/// Tiles covered by one repetition of the wave normal map
+const WAVE_SIZE: f32 = 48.0;
+/// Texels per side of the wave normal map
+const WAVE_TEXELS: u32 = 128;
+/// Distance the waves move per second, in repetitions of the normal map
+const WAVE_SPEED: Vec2 = Vec2::new(0.015, 0.01);
+
+/// Marks the water surface of a terrain chunk, a child of the chunk's entity
+#[derive(Component)]
+pub struct Water;
+
+/// Material shared by the water of all chunks
+#[derive(Resource)]
+pub struct WaterMaterial(pub Handle<StandardMaterial>);
+
+/// Tiling normal map of a few crossing waves, in tangent space
+fn wave_normal_map() -> Image {
+    // Whole numbers of periods across the map so it tiles: (periods along u, periods along v, amplitude, phase)
+    let waves = [(3.0, 1.0, 0.05, 0.0), (-2.0, 4.0, 0.03, 1.3), (7.0, -5.0, 0.012, 2.1), (-11.0, -9.0, 0.006, 4.4)];
+    let mut data = Vec::with_capacity((WAVE_TEXELS * WAVE_TEXELS * 4) as usize);
+    for v in 0..WAVE_TEXELS {
+        for u in 0..WAVE_TEXELS {
+            let (u, v) = (u as f32 / WAVE_TEXELS as f32, v as f32 / WAVE_TEXELS as f32);
+            let (mut du, mut dv) = (0.0, 0.0);
+            for (periods_u, periods_v, amplitude, phase) in waves {
+                let slope = amplitude * TAU * (TAU * (periods_u * u + periods_v * v) + phase).cos();
+                du += slope * periods_u;
+                dv += slope * periods_v;
+            }
+            let normal = Vec3::new(-du, -dv, 1.0).normalize();
+            let encoded = (normal * 0.5 + Vec3::splat(0.5)) * 255.0;
+            data.extend_from_slice(&[encoded.x as u8, encoded.y as u8, encoded.z as u8, 255]);
+        }
+    }
+
+    let mut image = Image::new(
+        Extent3d { width: WAVE_TEXELS, height: WAVE_TEXELS, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8Unorm,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::Repeat,
+        address_mode_v: ImageAddressMode::Repeat,
+        ..ImageSamplerDescriptor::linear()
+    });
+    image
+}
+
+/// Material shared by the water of all chunks
+pub fn water_material(materials: &mut Assets<StandardMaterial>, images: &mut Assets<Image>, params: &WaterParams) -> Handle<StandardMaterial> {
+    materials.add(StandardMaterial {
+        base_color: Color::WHITE,
+        alpha_mode: AlphaMode::Blend,
+        perceptual_roughness: 0.1,
+        reflectance: 0.6,
+        normal_map_texture: params.waves.then(|| images.add(wave_normal_map())),
+        ..default()
+    })
+}
+
+/// Mesh asset of a chunk's water, with texture coordinates that continue across chunks so the waves line up
+pub fn water_mesh_asset(chunk: &TerrainChunk, water: TerrainMesh) -> Mesh {
+    let uvs: Vec<[f32; 2]> = water
+        .positions
+        .iter()
+        .map(|&[x, _, z]| [(chunk.x as f32 + x) / WAVE_SIZE, (chunk.z as f32 + z) / WAVE_SIZE])
+        .collect();
+    // The water is flat, u runs along x
+    let tangents = VertexAttributeValues::Float32x4(vec![[1.0, 0.0, 0.0, 1.0]; uvs.len()]);
+    Mesh::from(water)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_TANGENT, tangents)
+}
+
+/// Replaces the mesh of a chunk's water, water meshes are empty where no vertex of the chunk is under water
+pub fn set_water_mesh(
+    meshes: &mut Assets<Mesh>,
+    handle: &Handle<Mesh>,
+    aabb: &mut Aabb,
+    visibility: &mut Visibility,
+    chunk: &TerrainChunk,
+    water: TerrainMesh,
+) {
+    if water.indices.is_empty() {
+        *visibility = Visibility::Hidden;
+        return;
+    }
+    let (min, max) = water.bounds();
+    *aabb = Aabb::from_min_max(Vec3::from(min), Vec3::from(max));
+    meshes.insert(handle, water_mesh_asset(chunk, water));
+    *visibility = Visibility::Inherited;
+}
+
+/// Spawns the water of a chunk as a child of the chunk's entity, nothing is spawned while `water` is empty
+pub fn spawn_water(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    material: &Handle<StandardMaterial>,
+    parent: Entity,
+    chunk: &TerrainChunk,
+    water: TerrainMesh,
+) {
+    if water.indices.is_empty() {
+        return;
+    }
+    let (min, max) = water.bounds();
+    let water = commands
+        .spawn((
+            Mesh3d(meshes.add(water_mesh_asset(chunk, water))),
+            MeshMaterial3d(material.clone()),
+            Transform::default(),
+            Aabb::from_min_max(Vec3::from(min), Vec3::from(max)),
+            Water,
+        ))
+        .id();
+    commands.entity(parent).add_child(water);
+}
+
+/// Scrolls the wave normal map of the water
+pub fn animate_water(time: Res<Time>, material: Option<Res<WaterMaterial>>, mut materials: ResMut<Assets<StandardMaterial>>) {
+    let Some(material) = material else {
+        return;
+    };
+    let Some(material) = materials.get_mut(&material.0) else {
+        return;
+    };
+    if material.normal_map_texture.is_some() {
+        material.uv_transform = Affine2::from_translation(WAVE_SPEED * time.elapsed_secs());
+    }
+}
@@ -1,33 +1,258 @@
-use super::tile::{OCEAN, SEA_LEVEL, Biome, WorldMap};
-
-/// Assigns a biome to each tile in the world map based on height, temperature, and rainfall
-pub fn assign_biome(world_map: &mut WorldMap) {
-    println!("Assigning Biomes...");
-    let size = world_map.get_size() as usize;
-
-    for x in 0..size {
-        for z in 0..size {
-            let tile = &mut world_map.tiles[x][z];
-            let height = tile.height;
-            let temperature = tile.temperature;
-            let rainfall = tile.rainfall;
-
-            tile.biome = Some(
-                if height < OCEAN {
-                    Biome::DeepOcean
-                } else if height < SEA_LEVEL {
-                    // Assign shallow water biomes
-                    if rainfall > 0.8 && temperature > 0.6 {
-                        Biome::CoralReef
-                    } else if rainfall > 0.6 {
-                        Biome::ShallowSea
-                    } else {
-                        Biome::ShallowOcean
-                    }
-                } else {
-                    Biome::Grassland
-                },
-            );
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use rayon::prelude::*;
+use super::progress::{Cancelled, Progress};
+use super::tile::{MAX_HEIGHT, Biome, WorldMap};
+
+/// Default location of the biome table, relative to the working directory
+pub const BIOMES_PATH: &str = "resources/biomes.txt";
+
+/// Copy of the biome table compiled into the binary, used when `BIOMES_PATH` cannot be read
+const DEFAULT_BIOMES: &str = include_str!("../../resources/biomes.txt");
+
+/// Normalized height of the sea level in the biome table.
+/// Everything in 0.0 - 0.1 is underwater, 0.1 - 1.0 is land up to `MAX_HEIGHT`
+const SEA_LEVEL_NORMALIZED: f32 = 0.1;
+
+/// Biomes placed by the hydrology stage instead of the climate ranges
+pub const LAKE: &str = "Lake";
+pub const RIVER: &str = "River";
+
+static REGISTRY: OnceLock<BiomeRegistry> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum BiomeError {
+    Io(std::io::Error),
+    Parse { line: usize, message: String },
+    Empty,
+    TooManyBiomes(usize),
+}
+
+impl fmt::Display for BiomeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BiomeError::Io(err) => write!(f, "could not read biome table: {}", err),
+            BiomeError::Parse { line, message } => write!(f, "biome table line {}: {}", line, message),
+            BiomeError::Empty => write!(f, "biome table does not define any biomes"),
+            BiomeError::TooManyBiomes(count) => write!(f, "biome table defines {} biomes, at most {} are supported", count, u8::MAX),
+        }
+    }
+}
+
+impl std::error::Error for BiomeError {}
+
+impl From<std::io::Error> for BiomeError {
+    fn from(err: std::io::Error) -> Self {
+        BiomeError::Io(err)
+    }
+}
+
+/// Inclusive range of a climate parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Range {
+    /// How far `value` lies outside of the range, 0.0 if it is inside
+    fn distance(&self, value: f32) -> f32 {
+        (self.min - value).max(value - self.max).max(0.0)
+    }
+
+    /// Distance to the center of the range relative to its width
+    fn center_distance(&self, value: f32) -> f32 {
+        let half_width = ((self.max - self.min) / 2.0).max(f32::EPSILON);
+        (value - (self.min + self.max) / 2.0).abs() / half_width
+    }
+}
+
+/// A single row of the biome table
+#[derive(Debug, Clone)]
+pub struct BiomeDef {
+    pub name: String,
+    pub rainfall: Range,
+    pub temperature: Range,
+    /// Normalized height, 0.0 = ocean floor, 0.1 = sea level, 1.0 = `MAX_HEIGHT`
+    pub height: Range,
+    pub color: [u8; 3],
+    pub description: String,
+}
+
+impl BiomeDef {
+    /// Aquatic biomes only live below sea level
+    pub fn is_aquatic(&self) -> bool {
+        self.height.max <= SEA_LEVEL_NORMALIZED
+    }
+
+    /// Lakes and rivers come from the hydrology stage, the climate never picks them
+    pub fn is_fresh_water(&self) -> bool {
+        self.name == LAKE || self.name == RIVER
+    }
+}
+
+/// Runtime list of biomes, `Biome` IDs index into it
+#[derive(Debug, Clone)]
+pub struct BiomeRegistry {
+    biomes: Vec<BiomeDef>,
+}
+
+impl BiomeRegistry {
+    /// Parses a biome table in the format of `resources/biomes.txt`.
+    /// The first line is a header, empty lines and lines starting with `#` are skipped.
+    /// Every other line reads: `Name  rain_min - rain_max  temp_min - temp_max  height_min - height_max  r, g, b  Description`
+    pub fn parse(text: &str) -> Result<Self, BiomeError> {
+        let mut biomes = Vec::new();
+
+        for (index, line) in text.lines().enumerate().skip(1) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| BiomeError::Parse { line: index + 1, message: message.to_string() };
+
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.len() < 13 {
+                return Err(error("expected a name, three ranges and a color"));
+            }
+
+            let range = |start: usize| -> Result<Range, BiomeError> {
+                if tokens[start + 1] != "-" {
+                    return Err(error("ranges are written as `min - max`"));
+                }
+                let min = tokens[start].parse::<f32>().map_err(|_| error("invalid range minimum"))?;
+                let max = tokens[start + 2].parse::<f32>().map_err(|_| error("invalid range maximum"))?;
+                if min > max {
+                    return Err(error("range minimum is larger than its maximum"));
+                }
+                Ok(Range { min, max })
+            };
+
+            let mut color = [0u8; 3];
+            for (channel, token) in color.iter_mut().zip(&tokens[10..13]) {
+                *channel = token.trim_end_matches(',').parse::<u8>().map_err(|_| error("colors are written as `r, g, b` with values 0 - 255"))?;
+            }
+
+            biomes.push(BiomeDef {
+                name: tokens[0].to_string(),
+                rainfall: range(1)?,
+                temperature: range(4)?,
+                height: range(7)?,
+                color,
+                description: tokens[13..].join(" "),
+            });
         }
+
+        if biomes.is_empty() {
+            return Err(BiomeError::Empty);
+        }
+        if biomes.len() > u8::MAX as usize {
+            return Err(BiomeError::TooManyBiomes(biomes.len()));
+        }
+
+        Ok(BiomeRegistry { biomes })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BiomeError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Registry shared by the whole program.
+    /// Loaded from `BIOMES_PATH` on first use, falls back to the table compiled into the binary.
+    pub fn global() -> &'static BiomeRegistry {
+        REGISTRY.get_or_init(|| {
+            Self::load(BIOMES_PATH).unwrap_or_else(|err| {
+                eprintln!("Using built-in biomes, {}", err);
+                Self::parse(DEFAULT_BIOMES).expect("built-in biome table is valid")
+            })
+        })
+    }
+
+    pub fn get(&self, biome: Biome) -> &BiomeDef {
+        &self.biomes[biome.0 as usize]
+    }
+
+    pub fn find(&self, name: &str) -> Option<Biome> {
+        self.biomes.iter().position(|def| def.name == name).map(|index| Biome(index as u8))
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Biome, &BiomeDef)> {
+        self.biomes.iter().enumerate().map(|(index, def)| (Biome(index as u8), def))
+    }
+
+    /// Picks the biome whose ranges match the given climate best.
+    /// Tiles below sea level only get aquatic biomes and tiles above only land biomes.
+    /// If no biome contains the values, the one with the closest ranges is used.
+    pub fn classify(&self, height: f32, temperature: f32, rainfall: f32, sea_level: f32) -> Biome {
+        let underwater = height < sea_level;
+        let height = normalized_height(height, sea_level);
+
+        let mut best: Option<(Biome, f32, f32)> = None;
+        for (biome, def) in self.iter() {
+            if def.is_aquatic() != underwater || def.is_fresh_water() {
+                continue;
+            }
+
+            // Temperature spans -1.0 - 1.0, so it counts half per unit
+            let outside = def.rainfall.distance(rainfall)
+                + def.temperature.distance(temperature) / 2.0
+                + def.height.distance(height);
+            let center = def.rainfall.center_distance(rainfall)
+                + def.temperature.center_distance(temperature)
+                + def.height.center_distance(height);
+
+            let is_better = match best {
+                None => true,
+                Some((_, best_outside, best_center)) => {
+                    outside < best_outside || (outside == best_outside && center < best_center)
+                }
+            };
+            if is_better {
+                best = Some((biome, outside, center));
+            }
+        }
+
+        // A table with only land or only water biomes still has to produce something
+        best.map(|(biome, _, _)| biome).unwrap_or(Biome(0))
+    }
+}
+
+/// Maps a tile height onto the 0.0 - 1.0 scale used by the biome table
+fn normalized_height(height: f32, sea_level: f32) -> f32 {
+    if height < sea_level {
+        (height / sea_level).clamp(0.0, 1.0) * SEA_LEVEL_NORMALIZED
+    } else {
+        let land = ((height - sea_level) / (MAX_HEIGHT - sea_level)).clamp(0.0, 1.0);
+        SEA_LEVEL_NORMALIZED + land * (1.0 - SEA_LEVEL_NORMALIZED)
+    }
+}
+
+/// Assigns a biome to each tile in the world map based on height, temperature, and rainfall.
+/// Lakes and rivers get the `Lake` and `River` biomes if the table defines them.
+pub fn assign_biome(world_map: &mut WorldMap, progress: &Progress) -> Result<(), Cancelled> {
+    progress.begin("Assigning biomes")?;
+    let registry = BiomeRegistry::global();
+    let lake = registry.find(LAKE);
+    let river = registry.find(RIVER);
+
+    let sea_level = world_map.sea_level();
+
+    let biomes: Vec<u8> = world_map
+        .par_tiles()
+        .map(|(i, tile)| {
+            let fresh_water = if tile.river {
+                river
+            } else if world_map.is_lake(i) {
+                lake
+            } else {
+                None
+            };
+            fresh_water.unwrap_or_else(|| registry.classify(tile.height, tile.temperature, tile.rainfall, sea_level)).0
+        })
+        .collect();
+    world_map.biomes = biomes;
+    Ok(())
 }
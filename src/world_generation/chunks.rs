@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[cfg(feature = "render")]
+use bevy::ecs::system::Resource;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use super::pipeline::generate_with_progress;
+use super::progress::{Cancelled, Progress};
+use super::settings::GenerationSettings;
+use super::tile::{Tile, WorldMap};
+// Chunked world storage
+// Chunks are square world maps keyed by their chunk coordinates, generated the first time they are requested
+// Only the most recently used chunks are kept, the rest are dropped and regenerated when needed again
+// Noise is sampled at global tile coordinates, so chunks are not limited to `GenerationSettings::world_size`
+
+/// Position of a chunk, chunk (1, 0) starts `chunk_size` tiles after chunk (0, 0) along x
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkCoord {
+    pub x: i32,
+    pub z: i32,
+}
+
+impl ChunkCoord {
+    pub fn new(x: i32, z: i32) -> Self {
+        ChunkCoord { x, z }
+    }
+
+    /// Chunk containing the global tile, together with the position of the tile inside the chunk
+    pub fn from_tile(global_x: i64, global_z: i64, chunk_size: u32) -> (ChunkCoord, usize, usize) {
+        let chunk_size = chunk_size as i64;
+        let coord = ChunkCoord::new(global_x.div_euclid(chunk_size) as i32, global_z.div_euclid(chunk_size) as i32);
+        (coord, global_x.rem_euclid(chunk_size) as usize, global_z.rem_euclid(chunk_size) as usize)
+    }
+
+    /// Global tile coordinates of the first tile of the chunk
+    pub fn origin(&self, chunk_size: u32) -> (i64, i64) {
+        (self.x as i64 * chunk_size as i64, self.z as i64 * chunk_size as i64)
+    }
+}
+
+/// Tunables of chunked generation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChunkParams {
+    /// Width of a chunk in tiles, the larger the chunk the less of the work goes into its apron
+    pub chunk_size: u32,
+    /// Number of chunks kept in memory
+    pub capacity: usize,
+}
+
+impl Default for ChunkParams {
+    fn default() -> Self {
+        ChunkParams {
+            chunk_size: 2048,
+            capacity: 16,
+        }
+    }
+}
+
+/// Tiles generated around every chunk and cropped afterwards, so that the heights and temperatures
+/// of the chunk come out exactly as in a single map covering its neighbours.
+/// It adds up how far each stage looks at the tiles around it:
+/// the deep ocean checks the island chunks next to its own, the coast blur reaches `coast_radius`,
+/// every erosion pass reads whole blocks and every slope relaxation step reaches two tiles.
+/// Rivers, lakes and rainfall depend on whole catchments and wind paths, they only see the chunk and its apron
+/// and can change where those cross a chunk border.
+pub fn apron(settings: &GenerationSettings) -> u32 {
+    2 * settings.islands.deep_ocean_chunk_size
+        + settings.relief.coast_radius as u32
+        + settings.erosion.passes * settings.erosion.block_size.max(2) as u32
+        + 2 * settings.thermal.iterations
+}
+
+/// Generates a single chunk of the world
+pub fn generate_chunk(settings: &GenerationSettings, coord: ChunkCoord) -> WorldMap {
+    generate_chunk_with_progress(settings, coord, &Progress::default()).expect("generation without a cancellation token is never cancelled")
+}
+
+/// Generates a single chunk of the world, reporting the stages to `progress` and stopping once it is cancelled
+pub fn generate_chunk_with_progress(settings: &GenerationSettings, coord: ChunkCoord, progress: &Progress) -> Result<WorldMap, Cancelled> {
+    let params = &settings.chunks;
+    let (origin_x, origin_z) = coord.origin(params.chunk_size);
+    let apron = apron(settings);
+
+    let mut world_map = WorldMap::with_origin(params.chunk_size + 2 * apron, (origin_x - apron as i64, origin_z - apron as i64));
+    generate_with_progress(&mut world_map, settings, progress)?;
+    Ok(world_map.crop(apron as usize, apron as usize, params.chunk_size))
+}
+
+struct CachedChunk {
+    world_map: Arc<WorldMap>,
+    last_used: u64,
+}
+
+/// A world without fixed size, generated chunk by chunk on demand
+#[cfg_attr(feature = "render", derive(Resource))]
+pub struct ChunkedWorld {
+    settings: GenerationSettings,
+    chunks: HashMap<ChunkCoord, CachedChunk>,
+    /// Increases on every access, used to find the least recently used chunk
+    clock: u64,
+}
+
+impl ChunkedWorld {
+    pub fn new(settings: GenerationSettings) -> Self {
+        ChunkedWorld { settings, chunks: HashMap::new(), clock: 0 }
+    }
+
+    pub fn chunk_size(&self) -> u32 {
+        self.settings.chunks.chunk_size
+    }
+
+    /// Returns the chunk, generating it if it is not in the cache
+    pub fn chunk(&mut self, coord: ChunkCoord) -> Arc<WorldMap> {
+        self.chunk_with_progress(coord, &Progress::default()).expect("generation without a cancellation token is never cancelled")
+    }
+
+    /// Returns the chunk, generating it with `progress` if it is not in the cache
+    pub fn chunk_with_progress(&mut self, coord: ChunkCoord, progress: &Progress) -> Result<Arc<WorldMap>, Cancelled> {
+        self.clock += 1;
+        if let Some(cached) = self.chunks.get_mut(&coord) {
+            cached.last_used = self.clock;
+            return Ok(cached.world_map.clone());
+        }
+
+        let world_map = Arc::new(generate_chunk_with_progress(&self.settings, coord, progress)?);
+        self.insert(coord, world_map.clone());
+        Ok(world_map)
+    }
+
+    /// One map of `count` x `count` chunks starting at `first`, the missing chunks are generated with `progress`
+    pub fn area(&mut self, first: ChunkCoord, count: u32, progress: &Progress) -> Result<WorldMap, Cancelled> {
+        let chunk_size = self.chunk_size();
+        let mut area = WorldMap::with_origin(count * chunk_size, first.origin(chunk_size));
+        area.set_sea_level(self.settings.sea_level);
+        for i in 0..count {
+            for j in 0..count {
+                let chunk = self.chunk_with_progress(ChunkCoord::new(first.x + i as i32, first.z + j as i32), progress)?;
+                area.paste(&chunk, (i * chunk_size) as usize, (j * chunk_size) as usize);
+            }
+        }
+        Ok(area)
+    }
+
+    /// Generates all missing chunks in parallel, e.g. every chunk of an area before it is written out
+    pub fn prefetch(&mut self, coords: &[ChunkCoord]) {
+        let missing: Vec<ChunkCoord> = coords.iter().copied().filter(|coord| !self.chunks.contains_key(coord)).collect();
+        let generated: Vec<(ChunkCoord, WorldMap)> = missing
+            .into_par_iter()
+            .map(|coord| (coord, generate_chunk(&self.settings, coord)))
+            .collect();
+
+        for (coord, world_map) in generated {
+            self.clock += 1;
+            self.insert(coord, Arc::new(world_map));
+        }
+    }
+
+    /// Returns the chunk only if it is already generated
+    pub fn get(&self, coord: ChunkCoord) -> Option<Arc<WorldMap>> {
+        self.chunks.get(&coord).map(|cached| cached.world_map.clone())
+    }
+
+    /// Tile at global tile coordinates, generating its chunk if needed
+    pub fn tile(&mut self, global_x: i64, global_z: i64) -> Tile {
+        let (coord, x, z) = ChunkCoord::from_tile(global_x, global_z, self.chunk_size());
+        self.chunk(coord).tile(x, z)
+    }
+
+    pub fn loaded_chunks(&self) -> impl Iterator<Item = ChunkCoord> + '_ {
+        self.chunks.keys().copied()
+    }
+
+    fn insert(&mut self, coord: ChunkCoord, world_map: Arc<WorldMap>) {
+        while self.chunks.len() >= self.settings.chunks.capacity.max(1) {
+            let oldest = self.chunks.iter().min_by_key(|(_, cached)| cached.last_used).map(|(coord, _)| *coord);
+            match oldest {
+                Some(oldest) => self.chunks.remove(&oldest),
+                None => break,
+            };
+        }
+        self.chunks.insert(coord, CachedChunk { world_map, last_used: self.clock });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every stage scaled down so a chunk and its apron stay small, without rivers so the heights only depend on the apron
+    fn small_settings() -> GenerationSettings {
+        let mut settings = GenerationSettings { world_size: 256, ..Default::default() };
+        settings.islands.chunk_sizes = vec![64, 32];
+        settings.islands.deep_ocean_chunk_size = 16;
+        settings.relief.coast_radius = 4;
+        settings.erosion.iterations = 50;
+        settings.erosion.block_size = 8;
+        settings.thermal.iterations = 2;
+        settings.hydrology.river_threshold = f32::MAX;
+        settings.chunks = ChunkParams { chunk_size: 32, capacity: 2 };
+        settings
+    }
+
+    #[test]
+    fn chunks_match_a_single_map() {
+        let settings = small_settings();
+        let apron = apron(&settings);
+        let mut whole = WorldMap::with_origin(64 + 2 * apron, (-(apron as i64), -(apron as i64)));
+        generate_with_progress(&mut whole, &settings, &Progress::default()).unwrap();
+        let whole = whole.crop(apron as usize, apron as usize, 64);
+
+        let mut world = ChunkedWorld::new(settings);
+        let area = world.area(ChunkCoord::new(0, 0), 2, &Progress::default()).unwrap();
+        assert_eq!(area.origin(), (0, 0));
+        assert_eq!(area.heights, whole.heights);
+        assert_eq!(area.temperatures, whole.temperatures);
+        assert!(area.heights.iter().any(|&height| height >= area.sea_level()), "the area should not be all sea");
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_chunk() {
+        let mut world = ChunkedWorld::new(small_settings());
+        let [a, b, c] = [ChunkCoord::new(0, 0), ChunkCoord::new(-1, 0), ChunkCoord::new(0, 5)];
+        let first = world.chunk(a);
+        world.chunk(b);
+        assert!(Arc::ptr_eq(&first, &world.chunk(a)));
+        world.chunk(c);
+
+        let mut loaded: Vec<_> = world.loaded_chunks().collect();
+        loaded.sort_by_key(|coord| (coord.x, coord.z));
+        assert_eq!(loaded, [a, c]);
+        assert!(world.get(b).is_none());
+        assert_eq!(world.get(a).unwrap().origin(), (0, 0));
+        assert_eq!(world.get(c).unwrap().origin(), (0, 160));
+        assert_eq!(world.tile(-1, 3).height, world.chunk(b).tile(31, 3).height);
+    }
+}
@@ -1,48 +1,228 @@
-use noise::{NoiseFn, Perlin, Simplex};
-use super::tile::WorldMap;
+use std::f32::consts::FRAC_PI_2;
 
-/// Generates the climate of a region based on temperate and rainfall
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use super::progress::{Cancelled, Progress};
+use super::settings::GenerationSettings;
+use super::tile::{SEA_LEVEL, MAX_HEIGHT, TEMPERATURE_RANGE, WorldMap, noise_coords, quantize};
+
+/// Where the warm and cold regions of the map are
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Latitude {
+    /// Warmest at `position` (0.0 = first row, 1.0 = last row of the world) and coldest at the edge furthest away
+    Equator { position: f32 },
+    /// Warmest at the first row of the world and coldest at the last, like a single hemisphere
+    Gradient,
+}
+
+/// Tunables of the temperature model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TemperatureParams {
+    pub latitude: Latitude,
+    pub equator_temperature: f32,
+    pub pole_temperature: f32,
+    /// Temperature drop per unit of height above sea level
+    pub lapse_rate: f32,
+    /// Strength of the noise on top of latitude and altitude
+    pub noise_amplitude: f32,
+    /// Number of noise features across the world
+    pub noise_frequency: f64,
+}
+
+impl Default for TemperatureParams {
+    fn default() -> Self {
+        TemperatureParams {
+            latitude: Latitude::Equator { position: 0.5 },
+            equator_temperature: 1.0,
+            pole_temperature: -1.0,
+            // The highest peaks are 1.2 colder than the coast
+            lapse_rate: 1.2 / (MAX_HEIGHT - SEA_LEVEL),
+            noise_amplitude: 0.15,
+            noise_frequency: 8.0,
+        }
+    }
+}
+
+/// Temperature at a latitude, `latitude` goes from 0.0 at the first row to 1.0 at the last row of the world
+fn latitude_temperature(latitude: f32, params: &TemperatureParams) -> f32 {
+    let distance = match params.latitude {
+        Latitude::Equator { position } => {
+            let furthest = position.max(1.0 - position).max(f32::EPSILON);
+            ((latitude - position).abs() / furthest).min(1.0)
+        }
+        Latitude::Gradient => latitude.clamp(0.0, 1.0),
+    };
+    // Mild around the equator, quickly colder towards the poles
+    let coldness = 1.0 - (distance * FRAC_PI_2).cos();
+    params.equator_temperature + (params.pole_temperature - params.equator_temperature) * coldness
+}
+
+/// Computes the temperature of every tile from its latitude and height
 /// Temperature can be from -1.0 (freezing) to 1.0 (Warm)
+pub fn generate_temperature_layer(world_map: &mut WorldMap, seed: u32, world_size: u32, params: &TemperatureParams) {
+    let size = world_map.get_size() as usize;
+    let origin = world_map.origin();
+    let sea_level = world_map.sea_level();
+    let noise = Fbm::<Perlin>::new(seed).set_octaves(4);
+    let heights = &world_map.heights;
+
+    world_map.temperatures.par_chunks_mut(size).enumerate().for_each(|(x, row)| {
+        for (z, temperature) in row.iter_mut().enumerate() {
+            let [nx, nz] = noise_coords(origin, world_size, x, z);
+
+            let base = latitude_temperature(nz as f32, params);
+            // The sea surface does not get colder with the depth of the ocean floor
+            let altitude = (heights[x * size + z] - sea_level).max(0.0) * params.lapse_rate;
+            let perturbation = noise.get([nx * params.noise_frequency, nz * params.noise_frequency]) as f32 * params.noise_amplitude;
+
+            *temperature = quantize(base - altitude + perturbation, TEMPERATURE_RANGE);
+        }
+    });
+}
+
+/// Tunables of the wind and moisture model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RainfallParams {
+    /// Direction the prevailing wind blows towards in degrees, 0.0 = along +x, 90.0 = along +z
+    pub wind_direction: f32,
+    /// How much the wind meanders sideways, in tiles per tile travelled
+    pub turbulence: f32,
+    /// Number of meanders across the world
+    pub turbulence_frequency: f64,
+    /// Moisture of the air entering the map, relative to what warm air can hold
+    pub initial_moisture: f32,
+    /// Share of the missing moisture picked up per tile of open water
+    pub evaporation: f32,
+    /// Share of the missing moisture picked up per tile of land from plants and soil
+    pub land_evaporation: f32,
+    /// Share of the moisture that rains out per tile of flat land
+    pub rain_rate: f32,
+    /// Extra share of the moisture that rains out per unit of height the air is pushed up
+    pub orographic_rate: f32,
+    /// Rainfall of flat land under saturated air
+    pub rainfall_scale: f32,
+}
+
+impl Default for RainfallParams {
+    fn default() -> Self {
+        RainfallParams {
+            wind_direction: 0.0,
+            turbulence: 0.5,
+            turbulence_frequency: 4.0,
+            initial_moisture: 0.5,
+            evaporation: 0.01,
+            land_evaporation: 0.0002,
+            rain_rate: 0.0005,
+            orographic_rate: 0.05,
+            rainfall_scale: 0.7,
+        }
+    }
+}
+
+/// Moisture warm air can hold compared to the warmest air
+fn moisture_capacity(temperature: f32) -> f32 {
+    0.3 + 0.7 * (temperature + 1.0) / 2.0
+}
+
+/// Linear interpolation in a line of values, clamped to its ends
+fn sample_line(line: &[f32], position: f32) -> f32 {
+    let position = position.clamp(0.0, (line.len() - 1) as f32);
+    let i = (position as usize).min(line.len() - 2);
+    let t = position - i as f32;
+    line[i] * (1.0 - t) + line[i + 1] * t
+}
+
+/// Carries moisture with the prevailing wind, picks it up over water and rains it out over land.
+/// Air pushed up a slope rains out quickly and leaves a dry rain shadow behind mountain ranges.
 /// Rainfall can be from 0.0 (arid) to 1.0 (Wet)
-pub fn generate_climate_layer(world_map: &mut WorldMap, seed: u32) {
-    let size = world_map.get_size();
-    let temperature_noise = Simplex::new(seed);
-    let rainfall_noise = Simplex::new(seed + 1);
-    let chunk_size = 2048;
-
-    // Generate temperature and rainfall at the chunk level
-    for chunk_x in (0..size).step_by(chunk_size as usize) {
-        for chunk_z in (0..size).step_by(chunk_size as usize) {
-            let nx = chunk_x as f64 / size as f64;
-            let nz = chunk_z as f64 / size as f64;
-
-            // Can take raw temperature values from Perlin noise
-            let temperature_value = temperature_noise.get([nx, nz]);
-            // Need to normalize the Perlin noise for rainfall
-            let rainfall_value = (rainfall_noise.get([nx, nz]) + 1.0) / 2.0;
-
-            let assigned_temperature = if temperature_value > 0.3 {
-                // Warm temperature
-                1.0
-            } else if temperature_value > -0.2 {
-                // Cold temperature
-                -0.3
-            } else {
-                // Freezing temperature
-                -1.0
-            };
-
-            for x in chunk_x..(chunk_x + chunk_size).min(size) {
-                for z in chunk_z..(chunk_z + chunk_size).min(size) {
-                    world_map.set_temperature(x as usize, z as usize, assigned_temperature);
-                    world_map.set_rainfall(x as usize, z as usize, rainfall_value as f32);
-                }
-            }
+pub fn generate_rainfall_layer(world_map: &mut WorldMap, seed: u32, world_size: u32, params: &RainfallParams, progress: &Progress) -> Result<(), Cancelled> {
+    let size = world_map.get_size() as usize;
+    let origin = world_map.origin();
+    let turbulence = Perlin::new(seed.wrapping_add(1));
+
+    let (wind_z, wind_x) = params.wind_direction.to_radians().sin_cos();
+    // The wind is swept line by line along its main axis, lines run across the wind
+    let along_x = wind_x.abs() >= wind_z.abs();
+    let (along, across) = if along_x { (wind_x, wind_z) } else { (wind_z, wind_x) };
+    let drift = across / along.abs();
+    let index = |line: usize, k: usize| if along_x { (line, k) } else { (k, line) };
+
+    // Surface the air passes over, the ocean and lakes count as flat water
+    let mut surface = vec![0.0f32; size * size];
+    let mut temperature = vec![0.0f32; size * size];
+    let mut water = vec![false; size * size];
+    for line in 0..size {
+        for k in 0..size {
+            let (x, z) = index(line, k);
+            let i = world_map.index(x, z);
+            surface[line * size + k] = world_map.heights[i].max(world_map.water_levels[i]).max(world_map.sea_level());
+            temperature[line * size + k] = world_map.temperature_at(i);
+            water[line * size + k] = world_map.is_ocean(i) || world_map.is_lake(i);
         }
     }
+
+    let mut rainfall = vec![0.0f32; size * size];
+    let mut moisture = vec![params.initial_moisture; size];
+    let mut previous_surface = vec![0.0f32; size];
+    for step in 0..size {
+        let line = if along > 0.0 { step } else { size - 1 - step };
+        let current = &surface[line * size..(line + 1) * size];
+        if step == 0 {
+            previous_surface.copy_from_slice(current);
+        }
+
+        let (next_moisture, line_rainfall): (Vec<f32>, Vec<f32>) = (0..size)
+            .into_par_iter()
+            .map(|k| {
+                let i = line * size + k;
+                let (x, z) = index(line, k);
+                let [nx, nz] = noise_coords(origin, world_size, x, z);
+                let meander = turbulence.get([nx * params.turbulence_frequency, nz * params.turbulence_frequency]) as f32 * params.turbulence;
+
+                // Air arriving here came from the previous line, shifted by the sideways drift
+                let upwind = k as f32 - drift - meander;
+                let mut air = sample_line(&moisture, upwind);
+                let uplift = surface[i] - sample_line(&previous_surface, upwind);
+                let capacity = moisture_capacity(temperature[i]);
+
+                let evaporation = if water[i] { params.evaporation } else { params.land_evaporation };
+                air += (capacity - air).max(0.0) * evaporation;
+
+                // Rising air rains out, sinking air does not, cold air cannot hold what it carries
+                let share = (params.rain_rate + params.orographic_rate * uplift).clamp(0.0, 1.0);
+                let precipitation = air * share + (air - capacity).max(0.0);
+                air -= precipitation.min(air);
+
+                let rainfall = if water[i] {
+                    (air / capacity).min(1.0)
+                } else {
+                    (precipitation / params.rain_rate * params.rainfall_scale).min(1.0)
+                };
+                (air, rainfall)
+            })
+            .unzip();
+
+        moisture = next_moisture;
+        rainfall[line * size..(line + 1) * size].copy_from_slice(&line_rainfall);
+        previous_surface.copy_from_slice(current);
+        progress.update((step + 1) as f32 / size as f32)?;
+    }
+
+    for line in 0..size {
+        for k in 0..size {
+            let (x, z) = index(line, k);
+            world_map.set_rainfall(x, z, rainfall[line * size + k]);
+        }
+    }
+    Ok(())
 }
 
-pub fn climate_stack(world_map: &mut WorldMap, seed: u32) {
-    println!("Generating climate...");
-    generate_climate_layer(world_map, seed);
-}
\ No newline at end of file
+pub fn climate_stack(world_map: &mut WorldMap, settings: &GenerationSettings, progress: &Progress) -> Result<(), Cancelled> {
+    progress.begin("Generating temperature")?;
+    generate_temperature_layer(world_map, settings.seed, settings.world_size, &settings.temperature);
+    progress.begin("Generating rainfall")?;
+    generate_rainfall_layer(world_map, settings.seed, settings.world_size, &settings.rainfall, progress)
+}
@@ -0,0 +1,302 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use super::progress::{Cancelled, Progress};
+use super::settings::GenerationSettings;
+use super::tile::WorldMap;
+// Particle based hydraulic erosion
+// Droplets run downhill, pick up material on steep slopes and drop it where they slow down
+// The map is split into blocks that are eroded in parallel, every pass shifts the blocks by half a block to hide the seams
+// Blocks follow global coordinates, a block that fits inside two maps is eroded the same in both
+
+/// Tunables of the hydraulic erosion stage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErosionParams {
+    /// Droplets simulated per block and pass
+    pub iterations: u32,
+    /// Number of passes over the whole map
+    pub passes: u32,
+    /// Width of the blocks that are eroded in parallel
+    pub block_size: usize,
+    /// Maximum number of steps of a droplet
+    pub max_lifetime: u32,
+    /// How much a droplet keeps its direction instead of following the slope, 0.0 - 1.0
+    pub inertia: f32,
+    /// Sediment a droplet can carry per unit of slope, speed and water
+    pub capacity: f32,
+    /// Capacity on flat ground, keeps droplets eroding on gentle slopes
+    pub min_capacity: f32,
+    /// Share of the excess sediment dropped per step, 0.0 - 1.0
+    pub deposition: f32,
+    /// Share of the free capacity eroded per step, 0.0 - 1.0
+    pub erosion: f32,
+    /// Share of the water that evaporates per step, 0.0 - 1.0
+    pub evaporation: f32,
+    pub gravity: f32,
+    /// Keep the sediment and water flux layers in the world map, 8 more bytes per tile.
+    /// The hydrology stage routes more water along the paths of the droplets when the water flux is kept.
+    pub keep_layers: bool,
+}
+
+impl Default for ErosionParams {
+    fn default() -> Self {
+        ErosionParams {
+            iterations: 1000,
+            passes: 2,
+            block_size: 256,
+            max_lifetime: 30,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_capacity: 0.01,
+            deposition: 0.3,
+            erosion: 0.3,
+            evaporation: 0.01,
+            gravity: 4.0,
+            keep_layers: true,
+        }
+    }
+}
+
+/// Copy of a part of the map that one thread erodes, planes are stored as `x * depth + z`
+struct Block {
+    sea_level: f32,
+    x0: usize,
+    z0: usize,
+    width: usize,
+    depth: usize,
+    heights: Vec<f32>,
+    sediment: Vec<f32>,
+    water_flux: Vec<f32>,
+}
+
+impl Block {
+    fn index(&self, x: usize, z: usize) -> usize {
+        x * self.depth + z
+    }
+
+    /// Interpolated height and gradient at a position inside the block
+    fn height_and_gradient(&self, px: f32, pz: f32) -> (f32, f32, f32) {
+        let (x, z) = (px as usize, pz as usize);
+        let (u, v) = (px - x as f32, pz - z as f32);
+
+        let h00 = self.heights[self.index(x, z)];
+        let h10 = self.heights[self.index(x + 1, z)];
+        let h01 = self.heights[self.index(x, z + 1)];
+        let h11 = self.heights[self.index(x + 1, z + 1)];
+
+        let gradient_x = (h10 - h00) * (1.0 - v) + (h11 - h01) * v;
+        let gradient_z = (h01 - h00) * (1.0 - u) + (h11 - h10) * u;
+        let height = h00 * (1.0 - u) * (1.0 - v) + h10 * u * (1.0 - v) + h01 * (1.0 - u) * v + h11 * u * v;
+
+        (height, gradient_x, gradient_z)
+    }
+
+    /// Adds `amount` to the four corners of the cell containing the position, negative amounts erode
+    fn deposit(&mut self, px: f32, pz: f32, amount: f32) {
+        let (x, z) = (px as usize, pz as usize);
+        let (u, v) = (px - x as f32, pz - z as f32);
+
+        for (cx, cz, weight) in [
+            (x, z, (1.0 - u) * (1.0 - v)),
+            (x + 1, z, u * (1.0 - v)),
+            (x, z + 1, (1.0 - u) * v),
+            (x + 1, z + 1, u * v),
+        ] {
+            let i = self.index(cx, cz);
+            self.heights[i] += amount * weight;
+            self.sediment[i] += amount * weight;
+        }
+    }
+
+    fn is_inside(&self, px: f32, pz: f32) -> bool {
+        px >= 0.0 && pz >= 0.0 && px < (self.width - 1) as f32 && pz < (self.depth - 1) as f32
+    }
+
+    fn simulate_droplet(&mut self, rng: &mut StdRng, params: &ErosionParams) {
+        let mut px = rng.random_range(0.0..(self.width - 1) as f32);
+        let mut pz = rng.random_range(0.0..(self.depth - 1) as f32);
+        let (mut dir_x, mut dir_z) = (0.0f32, 0.0f32);
+        let mut speed = 1.0f32;
+        let mut water = 1.0f32;
+        let mut sediment = 0.0f32;
+
+        for _ in 0..params.max_lifetime {
+            let (height, gradient_x, gradient_z) = self.height_and_gradient(px, pz);
+
+            // Droplets that reach the sea drop everything they carry
+            if height < self.sea_level {
+                self.deposit(px, pz, sediment);
+                return;
+            }
+
+            dir_x = dir_x * params.inertia - gradient_x * (1.0 - params.inertia);
+            dir_z = dir_z * params.inertia - gradient_z * (1.0 - params.inertia);
+            let length = (dir_x * dir_x + dir_z * dir_z).sqrt();
+            if length <= f32::EPSILON {
+                break;
+            }
+            dir_x /= length;
+            dir_z /= length;
+
+            let (old_x, old_z) = (px, pz);
+            px += dir_x;
+            pz += dir_z;
+            let i = self.index(old_x as usize, old_z as usize);
+            self.water_flux[i] += water;
+
+            // Droplets are confined to their block so blocks can run in parallel
+            if !self.is_inside(px, pz) {
+                self.deposit(old_x, old_z, sediment);
+                return;
+            }
+
+            let delta_height = self.height_and_gradient(px, pz).0 - height;
+            let capacity = (-delta_height * speed * water * params.capacity).max(params.min_capacity);
+
+            if sediment > capacity || delta_height > 0.0 {
+                // Uphill the droplet fills the pit behind it, otherwise it drops part of the excess
+                let amount = if delta_height > 0.0 {
+                    delta_height.min(sediment)
+                } else {
+                    (sediment - capacity) * params.deposition
+                };
+                sediment -= amount;
+                self.deposit(old_x, old_z, amount);
+            } else {
+                // Never dig deeper than the height difference, that would leave holes
+                let amount = ((capacity - sediment) * params.erosion).min(-delta_height);
+                sediment += amount;
+                self.deposit(old_x, old_z, -amount);
+            }
+
+            speed = (speed * speed - delta_height * params.gravity).max(0.0).sqrt();
+            water *= 1.0 - params.evaporation;
+        }
+
+        self.deposit(px, pz, sediment);
+    }
+}
+
+/// Local start and global index of the blocks along one axis of the map.
+/// Blocks are aligned to global coordinates, so neighbouring maps cut the world into the same blocks.
+fn block_starts(origin: i64, size: usize, block_size: usize, offset: usize) -> Vec<(usize, i64)> {
+    let block_size = block_size as i64;
+    let mut block = (origin - offset as i64).div_euclid(block_size);
+    let mut starts = vec![(0, block)];
+    loop {
+        block += 1;
+        let start = block * block_size + offset as i64 - origin;
+        if start >= size as i64 {
+            break;
+        }
+        starts.push((start as usize, block));
+    }
+    starts
+}
+
+/// Seed of the droplets of one block, mixed with the splitmix64 finalizer
+fn block_seed(seed: u32, pass: u32, block_x: i64, block_z: i64) -> u64 {
+    let mut hash = seed as u64;
+    for value in [pass as u64, block_x as u64, block_z as u64] {
+        hash = (hash ^ value).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        hash ^= hash >> 31;
+    }
+    hash
+}
+
+/// Erodes the heights of the world map with simulated rain droplets.
+/// Records the net deposited material and the water that flowed over each tile if `keep_layers` is set.
+/// The result only depends on the seed, not on the number of threads.
+pub fn erode(world_map: &mut WorldMap, seed: u32, params: &ErosionParams, progress: &Progress) -> Result<(), Cancelled> {
+    let size = world_map.get_size() as usize;
+    let block_size = params.block_size.max(2);
+
+    let tiles = world_map.heights.len();
+    world_map.sediment = params.keep_layers.then(|| vec![0.0; tiles]);
+    world_map.water_flux = params.keep_layers.then(|| vec![0.0; tiles]);
+    let origin = world_map.origin();
+    let sea_level = world_map.sea_level();
+
+    for pass in 0..params.passes {
+        // Every other pass the block grid is shifted by half a block
+        let offset = if pass % 2 == 1 { block_size / 2 } else { 0 };
+        let x_starts = block_starts(origin.0, size, block_size, offset);
+        let z_starts = block_starts(origin.1, size, block_size, offset);
+
+        let mut bounds = Vec::new();
+        for (i, &(x0, block_x)) in x_starts.iter().enumerate() {
+            for (j, &(z0, block_z)) in z_starts.iter().enumerate() {
+                let x1 = x_starts.get(i + 1).map_or(size, |start| start.0);
+                let z1 = z_starts.get(j + 1).map_or(size, |start| start.0);
+                bounds.push((x0, z0, x1 - x0, z1 - z0, block_seed(seed, pass, block_x, block_z)));
+            }
+        }
+
+        let heights = &world_map.heights;
+        let finished = AtomicUsize::new(0);
+        let blocks: Vec<Block> = bounds
+            .par_iter()
+            .filter(|&&(_, _, width, depth, _)| width > 1 && depth > 1)
+            .map(|&(x0, z0, width, depth, block_seed)| {
+                let mut block_heights = Vec::with_capacity(width * depth);
+                for x in x0..x0 + width {
+                    block_heights.extend_from_slice(&heights[x * size + z0..x * size + z0 + depth]);
+                }
+                let mut block = Block {
+                    sea_level,
+                    x0,
+                    z0,
+                    width,
+                    depth,
+                    heights: block_heights,
+                    sediment: vec![0.0; width * depth],
+                    water_flux: vec![0.0; width * depth],
+                };
+
+                if progress.is_cancelled() {
+                    return block;
+                }
+                let mut rng = StdRng::seed_from_u64(block_seed);
+                let droplets = params.iterations as usize * width * depth / (block_size * block_size);
+                for _ in 0..droplets {
+                    block.simulate_droplet(&mut rng, params);
+                }
+
+                let done = finished.fetch_add(1, Ordering::Relaxed) + 1;
+                // Cancellation is checked once all blocks are back
+                let _ = progress.update((pass as f32 + done as f32 / bounds.len() as f32) / params.passes as f32);
+                block
+            })
+            .collect();
+        progress.check()?;
+
+        for block in blocks {
+            for x in 0..block.width {
+                let start = (block.x0 + x) * size + block.z0;
+                let local = block.index(x, 0)..block.index(x, block.depth);
+                world_map.heights[start..start + block.depth].copy_from_slice(&block.heights[local.clone()]);
+                if let Some(sediment) = &mut world_map.sediment {
+                    for (sediment, added) in sediment[start..start + block.depth].iter_mut().zip(&block.sediment[local.clone()]) {
+                        *sediment += added;
+                    }
+                }
+                if let Some(water_flux) = &mut world_map.water_flux {
+                    for (water_flux, added) in water_flux[start..start + block.depth].iter_mut().zip(&block.water_flux[local]) {
+                        *water_flux += added;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn erosion_stack(world_map: &mut WorldMap, settings: &GenerationSettings, progress: &Progress) -> Result<(), Cancelled> {
+    progress.begin("Eroding terrain")?;
+    erode(world_map, settings.seed, &settings.erosion, progress)
+}
@@ -0,0 +1,239 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use super::progress::{Cancelled, Progress};
+use super::settings::GenerationSettings;
+use super::tile::{for_each_neighbor, WorldMap};
+// Rivers and lakes
+// Depressions are filled with a priority flood from the sea, the filled surface drains everywhere
+// Water follows the steepest descent (D8) and rivers are carved where enough of it comes together
+// Every land tile adds one unit of runoff, plus the water the erosion droplets ran over it if that layer was kept
+// Tiles that had to be filled are lakes
+
+/// Tunables of the hydrology stage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HydrologyParams {
+    /// Height added per tile when filling flats so that every tile drains
+    pub epsilon: f32,
+    /// Minimum depth of a filled depression to count as a lake
+    pub lake_min_depth: f32,
+    /// Runoff needed before a river starts, about the number of upstream tiles
+    pub river_threshold: f32,
+    /// Depth of a river where it starts
+    pub river_min_depth: f32,
+    /// Extra depth each time the flow of a river grows by a factor of e
+    pub river_depth_scale: f32,
+    /// Runoff added per unit of the erosion water flux, so rivers prefer the channels the droplets carved
+    pub erosion_flux_weight: f32,
+    /// Keep the flow layer in the world map, 4 more bytes per tile
+    pub keep_flow: bool,
+}
+
+impl Default for HydrologyParams {
+    fn default() -> Self {
+        HydrologyParams {
+            epsilon: 0.001,
+            lake_min_depth: 0.5,
+            river_threshold: 2000.0,
+            river_min_depth: 0.5,
+            river_depth_scale: 1.0,
+            erosion_flux_weight: 1.0,
+            keep_flow: false,
+        }
+    }
+}
+
+/// Marks tiles without a receiver
+const NO_RECEIVER: u32 = u32::MAX;
+
+/// Entry of the priority flood queue, the lowest height is popped first
+#[derive(PartialEq)]
+struct Cell {
+    height: f32,
+    index: u32,
+}
+
+impl Eq for Cell {}
+
+impl Ord for Cell {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.height.total_cmp(&self.height).then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for Cell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Fills every depression that cannot drain to the sea or the map border.
+/// Returns the filled heights and the land tiles from lowest to highest filled height.
+fn priority_flood(heights: &[f32], size: usize, sea_level: f32, epsilon: f32) -> (Vec<f32>, Vec<u32>) {
+    let mut filled = heights.to_vec();
+    let mut closed = vec![false; heights.len()];
+    let mut queue = BinaryHeap::new();
+    let mut order = Vec::new();
+
+    // The sea is the outlet, only its coast needs to be in the queue
+    for i in 0..heights.len() {
+        if heights[i] < sea_level {
+            closed[i] = true;
+            let mut is_coast = false;
+            for_each_neighbor(i, size, |neighbor, _| is_coast |= heights[neighbor] >= sea_level);
+            if is_coast {
+                queue.push(Cell { height: heights[i], index: i as u32 });
+            }
+        }
+    }
+
+    // Land on the border drains off the map
+    for i in 0..heights.len() {
+        let (x, z) = (i / size, i % size);
+        let on_border = x == 0 || z == 0 || x == size - 1 || z == size - 1;
+        if on_border && !closed[i] {
+            closed[i] = true;
+            queue.push(Cell { height: heights[i], index: i as u32 });
+        }
+    }
+
+    // Every tile is pushed at least `epsilon` above the one that reached it, so tiles are popped by rising filled height
+    while let Some(cell) = queue.pop() {
+        if heights[cell.index as usize] >= sea_level {
+            order.push(cell.index);
+        }
+        for_each_neighbor(cell.index as usize, size, |neighbor, _| {
+            if !closed[neighbor] {
+                closed[neighbor] = true;
+                filled[neighbor] = filled[neighbor].max(filled[cell.index as usize] + epsilon);
+                queue.push(Cell { height: filled[neighbor], index: neighbor as u32 });
+            }
+        });
+    }
+
+    (filled, order)
+}
+
+/// Steepest descent neighbour of every land tile on the filled surface
+fn flow_directions(filled: &[f32], size: usize, sea_level: f32) -> Vec<u32> {
+    let mut receivers = vec![NO_RECEIVER; filled.len()];
+    receivers.par_chunks_mut(size).enumerate().for_each(|(x, row)| {
+        for (z, receiver) in row.iter_mut().enumerate() {
+            let i = x * size + z;
+            if filled[i] < sea_level {
+                continue;
+            }
+            let mut steepest = 0.0;
+            for_each_neighbor(i, size, |neighbor, distance| {
+                let slope = (filled[i] - filled[neighbor]) / distance;
+                if slope > steepest {
+                    steepest = slope;
+                    *receiver = neighbor as u32;
+                }
+            });
+        }
+    });
+    receivers
+}
+
+/// Runoff of all land tiles draining through every tile, `order` as returned by `priority_flood`.
+/// Sea tiles only collect what flows into them.
+fn flow_accumulation(order: &[u32], receivers: &[u32], runoff: impl Fn(usize) -> f32) -> Vec<f32> {
+    // Receivers are always lower, so going from the highest tile down every tile is done before its receiver
+    let mut flow = vec![0.0f32; receivers.len()];
+    for &i in order.iter().rev() {
+        let i = i as usize;
+        flow[i] += runoff(i);
+        if receivers[i] != NO_RECEIVER {
+            flow[receivers[i] as usize] += flow[i];
+        }
+    }
+    flow
+}
+
+/// Fills depressions into lakes, routes water downhill and carves rivers.
+/// Sets the water level and river flag of every tile, and its flow if `keep_flow` is set.
+pub fn generate_hydrology(world_map: &mut WorldMap, params: &HydrologyParams, progress: &Progress) -> Result<(), Cancelled> {
+    let size = world_map.get_size() as usize;
+    let sea_level = world_map.sea_level();
+    // Filling the depressions takes most of the time
+    let (filled, order) = priority_flood(&world_map.heights, size, sea_level, params.epsilon);
+    progress.update(0.6)?;
+    let receivers = flow_directions(&filled, size, sea_level);
+    progress.update(0.7)?;
+
+    let flow = match &world_map.water_flux {
+        Some(water_flux) => flow_accumulation(&order, &receivers, |i| 1.0 + params.erosion_flux_weight * water_flux[i]),
+        None => flow_accumulation(&order, &receivers, |_| 1.0),
+    };
+    progress.update(0.8)?;
+
+    world_map.heights
+        .par_iter_mut()
+        .zip(world_map.water_levels.par_iter_mut())
+        .zip(world_map.rivers.par_iter_mut())
+        .enumerate()
+        .for_each(|(i, ((height, water_level), river))| {
+            *river = false;
+
+            if *height < sea_level {
+                *water_level = sea_level;
+            } else if filled[i] - *height >= params.lake_min_depth {
+                *water_level = filled[i];
+            } else if flow[i] >= params.river_threshold {
+                let depth = params.river_min_depth + params.river_depth_scale * (flow[i] / params.river_threshold).ln();
+                *river = true;
+                *water_level = *height;
+                *height = (*height - depth).max(sea_level);
+            } else {
+                *water_level = *height;
+            }
+        });
+    world_map.flows = params.keep_flow.then_some(flow);
+    Ok(())
+}
+
+pub fn hydrology_stack(world_map: &mut WorldMap, settings: &GenerationSettings, progress: &Progress) -> Result<(), Cancelled> {
+    progress.begin("Generating rivers and lakes")?;
+    generate_hydrology(world_map, &settings.hydrology, progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_land_flow_reaches_an_outlet() {
+        // A slope rising away from the sea along x, roughened enough to leave pits and ridges that need filling
+        let size = 32;
+        let sea_level = 10.0;
+        let mut state = 12345u32;
+        let heights: Vec<f32> = (0..size * size)
+            .map(|i| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let x = i / size;
+                if x == 0 {
+                    sea_level - 5.0
+                } else {
+                    sea_level + x as f32 * 0.5 + (state >> 16) as f32 / 65536.0 * 6.0
+                }
+            })
+            .collect();
+
+        let (filled, order) = priority_flood(&heights, size, sea_level, 0.001);
+        let receivers = flow_directions(&filled, size, sea_level);
+        let flow = flow_accumulation(&order, &receivers, |_| 1.0);
+
+        let land = heights.iter().filter(|&&height| height >= sea_level).count();
+        assert_eq!(order.len(), land);
+        // Water leaves the land into the sea or off the map border where a tile has no receiver
+        let outflow: f32 = (0..size * size)
+            .filter(|&i| heights[i] < sea_level || receivers[i] == NO_RECEIVER)
+            .map(|i| flow[i])
+            .sum();
+        assert_eq!(outflow, land as f32);
+    }
+}
@@ -1,86 +1,116 @@
+use std::ops::Range;
+
 use noise::{Fbm, NoiseFn, Perlin};
-use super::tile::{SEA_LEVEL, OCEAN, DEEP_OCEAN, WorldMap};
-/// This file generates the islands and island layer for the world
-/// Bottom of the ocean = 0.0
-/// Sea level = 60
-/// Highest peak = 280
+use serde::{Deserialize, Serialize};
+use super::progress::{Cancelled, Progress};
+use super::settings::GenerationSettings;
+use super::tile::{OCEAN, DEEP_OCEAN, WorldMap};
+// This file generates the islands and island layer for the world
+// Bottom of the ocean = 0.0
+// Sea level = 60 by default
+// Highest peak = 280
+// Chunks are aligned to global tile coordinates, so every part of the world can be generated on its own
+
+/// Tunables of the island stage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IslandParams {
+    /// Chunk sizes of the island passes, each pass decides land or water per chunk over the previous one
+    pub chunk_sizes: Vec<u32>,
+    /// Chunks of this size surrounded by ocean chunks become deep ocean
+    pub deep_ocean_chunk_size: u32,
+    /// Height of the ocean floor before the relief stage
+    pub ocean_height: f32,
+    /// Height of the deep ocean floor before the relief stage
+    pub deep_ocean_height: f32,
+}
+
+impl Default for IslandParams {
+    fn default() -> Self {
+        IslandParams {
+            chunk_sizes: vec![4096, 2048, 256],
+            deep_ocean_chunk_size: 256,
+            ocean_height: OCEAN,
+            deep_ocean_height: DEEP_OCEAN,
+        }
+    }
+}
 
+/// Global start coordinates of the chunks that overlap the world map
+fn overlapping_chunks(world_map: &WorldMap, chunk_size: u32) -> Vec<(i64, i64)> {
+    let chunk_size = chunk_size as i64;
+    let size = world_map.get_size() as i64;
+    let (origin_x, origin_z) = world_map.origin();
+
+    let first_x = origin_x.div_euclid(chunk_size) * chunk_size;
+    let first_z = origin_z.div_euclid(chunk_size) * chunk_size;
+    let mut chunks = Vec::new();
+    for chunk_x in (first_x..origin_x + size).step_by(chunk_size as usize) {
+        for chunk_z in (first_z..origin_z + size).step_by(chunk_size as usize) {
+            chunks.push((chunk_x, chunk_z));
+        }
+    }
+    chunks
+}
 
-fn generate_islands_4096(size: u32, seed: u32) -> WorldMap {
-    let mut world_map: WorldMap = WorldMap::new(size);
-    let noise = Fbm::<Perlin>::new(seed);
+/// Determines if the chunk starting at the global coordinates is land or water
+fn is_land_chunk(noise: &Fbm<Perlin>, chunk_x: i64, chunk_z: i64, chunk_size: u32, world_size: u32) -> bool {
+    // Determine the chunk center for noise evaluation
+    let center_x = chunk_x as f64 + (chunk_size as f64 / 2.0);
+    let center_z = chunk_z as f64 + (chunk_size as f64 / 2.0);
 
-    let chunk_size = 4096;
-    for chunk_x in (0..size).step_by(chunk_size as usize) {
-        for chunk_z in (0..size).step_by(chunk_size as usize) {
-            // Determine the chunk center for noise evaluation
-            let center_x = chunk_x as f64 + (chunk_size as f64 / 2.0);
-            let center_z = chunk_z as f64 + (chunk_size as f64 / 2.0);
-
-            // Normalize the center coordinates for noise input
-            let nx = center_x / size as f64;
-            let nz = center_z / size as f64;
-
-            // Get noise value to determine if this chunk is land or water
-            let noise_value = noise.get([nx, nz]);
-
-            // If noise value > 0.0, designate this chunk as land
-            if noise_value > 0.0 {
-                for x in chunk_x..(chunk_x + chunk_size).min(size) {
-                    for z in chunk_z..(chunk_z + chunk_size).min(size) {
-                        world_map.set_height(x as usize, z as usize, SEA_LEVEL);
-                    }
-                }
-            } else {
-                for x in chunk_x..(chunk_x + chunk_size).min(size) {
-                    for z in chunk_z..(chunk_z + chunk_size).min(size) {
-                        world_map.set_height(x as usize, z as usize, OCEAN);
-                    }
-                }
-            }
+    // Normalize the center coordinates for noise input
+    let nx = center_x / world_size as f64;
+    let nz = center_z / world_size as f64;
+
+    // If noise value > 0.0, designate this chunk as land
+    noise.get([nx, nz]) > 0.0
+}
+
+/// Local x and z ranges of the tiles of the chunk that lie inside the world map, empty if it is outside
+fn chunk_tiles(world_map: &WorldMap, chunk_x: i64, chunk_z: i64, chunk_size: u32) -> (Range<usize>, Range<usize>) {
+    let size = world_map.get_size() as i64;
+    let (origin_x, origin_z) = world_map.origin();
+    let local = |start: i64, origin: i64| {
+        let first = (start - origin).clamp(0, size) as usize;
+        let last = (start + chunk_size as i64 - origin).clamp(0, size) as usize;
+        first..last
+    };
+    (local(chunk_x, origin_x), local(chunk_z, origin_z))
+}
+
+/// Sets the height of every tile of the world map inside the chunk
+fn fill_chunk(world_map: &mut WorldMap, chunk_x: i64, chunk_z: i64, chunk_size: u32, height: f32) {
+    let (xs, zs) = chunk_tiles(world_map, chunk_x, chunk_z, chunk_size);
+    for x in xs {
+        for z in zs.clone() {
+            world_map.set_height(x, z, height);
         }
     }
-    world_map
 }
 
-fn generate_islands(world_map: &mut WorldMap, seed: u32, chunk_size: u32) {
+/// True if no tile of the chunk inside the world map is above `ocean_height`
+fn is_ocean_chunk(world_map: &WorldMap, chunk_x: i64, chunk_z: i64, chunk_size: u32, ocean_height: f32) -> bool {
+    let (xs, zs) = chunk_tiles(world_map, chunk_x, chunk_z, chunk_size);
+    xs.into_iter().all(|x| zs.clone().all(|z| world_map.get_height(x, z) <= ocean_height))
+}
+
+fn generate_islands(world_map: &mut WorldMap, seed: u32, world_size: u32, chunk_size: u32, params: &IslandParams) {
     let noise = Fbm::<Perlin>::new(seed);
-    let size = world_map.get_size();
-
-    for chunk_x in (0..size).step_by(chunk_size as usize) {
-        for chunk_z in (0..size).step_by(chunk_size as usize) {
-            // Determine the chunk center for noise evaluation
-            let center_x = chunk_x as f64 + (chunk_size as f64 / 2.0);
-            let center_z = chunk_z as f64 + (chunk_size as f64 / 2.0);
-
-            // Normalize the center coordinates for noise input
-            let nx = center_x / size as f64;
-            let nz = center_z / size as f64;
-
-            // Get noise value to determine if this chunk is land or water
-            let noise_value = noise.get([nx, nz]);
-
-            // Determine if this chunk is land or water
-            if noise_value > 0.0 {
-                for x in chunk_x..(chunk_x + chunk_size).min(size) {
-                    for z in chunk_z..(chunk_z + chunk_size).min(size) {
-                        world_map.set_height(x as usize, z as usize, SEA_LEVEL);
-                    }
-                }
-            } else {
-                for x in chunk_x..(chunk_x + chunk_size).min(size) {
-                    for z in chunk_z..(chunk_z + chunk_size).min(size) {
-                        world_map.set_height(x as usize, z as usize, OCEAN);
-                    }
-                }
-            }
+    let sea_level = world_map.sea_level();
+
+    for (chunk_x, chunk_z) in overlapping_chunks(world_map, chunk_size) {
+        // Determine if this chunk is land or water
+        if is_land_chunk(&noise, chunk_x, chunk_z, chunk_size, world_size) {
+            fill_chunk(world_map, chunk_x, chunk_z, chunk_size, sea_level);
+        } else {
+            fill_chunk(world_map, chunk_x, chunk_z, chunk_size, params.ocean_height);
         }
     }
 }
 
-fn add_deep_ocean(world_map: &mut WorldMap) {
-    let size = world_map.get_size();
-    let chunk_size: u32 = 256;
+fn add_deep_ocean(world_map: &mut WorldMap, params: &IslandParams) {
+    let chunk_size = params.deep_ocean_chunk_size;
 
     // Directions to check for neighboring chunks
     let neighbor_offsets = [
@@ -94,60 +124,29 @@ fn add_deep_ocean(world_map: &mut WorldMap) {
         (1, 1),
     ];
 
-    for chunk_x in (0..size).step_by(chunk_size as usize) {
-        for chunk_z in (0..size).step_by(chunk_size as usize) {
-            let mut is_surrounded_by_ocean = true;
-
-            // Check all neighboring chunks
-            for &(dx, dz) in &neighbor_offsets {
-                let neighbor_x = chunk_x as isize + dx * chunk_size as isize;
-                let neighbor_z = chunk_z as isize + dz * chunk_size as isize;
-
-                // Ensure neighbors are within bounds
-                if neighbor_x >= 0
-                    && neighbor_x < size as isize
-                    && neighbor_z >= 0
-                    && neighbor_z < size as isize
-                {
-                    // Check if this neighbor is ocean or below
-                    let mut neighbor_is_ocean = true;
-                    for x in neighbor_x as u32..(neighbor_x as u32 + chunk_size).min(size) {
-                        for z in neighbor_z as u32..(neighbor_z as u32 + chunk_size).min(size) {
-                            if world_map.get_height(x as usize, z as usize) > OCEAN {
-                                neighbor_is_ocean = false;
-                                break;
-                            }
-                        }
-                        if !neighbor_is_ocean {
-                            break;
-                        }
-                    }
-
-                    // If a neighbor is not ocean, this chunk is not deep ocean
-                    if !neighbor_is_ocean {
-                        is_surrounded_by_ocean = false;
-                        break;
-                    }
-                }
-            }
-
-            // If the chunk is completely surrounded by ocean, classify it as deep ocean
-            if is_surrounded_by_ocean {
-                for x in chunk_x..(chunk_x + chunk_size).min(size) {
-                    for z in chunk_z..(chunk_z + chunk_size).min(size) {
-                        world_map.set_height(x as usize, z as usize, DEEP_OCEAN);
-                    }
-                }
-            }
+    for (chunk_x, chunk_z) in overlapping_chunks(world_map, chunk_size) {
+        // Neighbours are checked by the heights the island passes left, tiles outside of the map are ignored
+        let is_surrounded_by_ocean = neighbor_offsets.iter().all(|&(dx, dz)| {
+            let neighbor_x = chunk_x + dx * chunk_size as i64;
+            let neighbor_z = chunk_z + dz * chunk_size as i64;
+            is_ocean_chunk(world_map, neighbor_x, neighbor_z, chunk_size, params.ocean_height)
+        });
+
+        // If the chunk is completely surrounded by ocean, classify it as deep ocean
+        if is_surrounded_by_ocean {
+            fill_chunk(world_map, chunk_x, chunk_z, chunk_size, params.deep_ocean_height);
         }
     }
 }
-pub fn island_stack(size: u32, seed: u32) -> WorldMap {
-    println!("Generating islands...");
-    let mut world_map = generate_islands_4096(size, seed);
-    generate_islands(&mut world_map, seed, 2048);
-    generate_islands(&mut world_map, seed, 256);
-    println!("Generating oceans...");
-    add_deep_ocean(&mut world_map);
-    world_map
-}
\ No newline at end of file
+
+pub fn island_stack(world_map: &mut WorldMap, settings: &GenerationSettings, progress: &Progress) -> Result<(), Cancelled> {
+    let params = &settings.islands;
+    progress.begin("Generating islands")?;
+    for (i, &chunk_size) in params.chunk_sizes.iter().enumerate() {
+        generate_islands(world_map, settings.seed, settings.world_size, chunk_size, params);
+        progress.update((i + 1) as f32 / params.chunk_sizes.len() as f32)?;
+    }
+    progress.begin("Generating oceans")?;
+    add_deep_ocean(world_map, params);
+    Ok(())
+}
@@ -1,60 +1,386 @@
-use bevy::prelude::*;
-use bevy::render::mesh::{Mesh, VertexAttributeValues};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use super::biomes::assign_biome;
-use super::islands::island_stack;
-use super::climate::climate_stack;
-use super::tile::{WorldMap, Biome};
+#[cfg(feature = "render")]
+use bevy::ecs::component::Component;
+#[cfg(feature = "render")]
+use bevy::render::{mesh::{Indices, Mesh, PrimitiveTopology, VertexAttributeValues}, render_asset::RenderAssetUsages};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use super::pipeline::generate_world_map_with_progress;
+use super::progress::{Cancelled, Progress};
+use super::settings::GenerationSettings;
+use super::tile::WorldMap;
+// Terrain meshes that do not depend on the renderer
+// The app shows the terrain as a grid of chunk meshes so Bevy can cull the ones out of view,
+// the exporters use a single mesh of the whole world
+// Chunks near the camera are meshed at full resolution and every level of detail further away doubles the vertex spacing,
+// skirts hanging down from the chunk edges hide the gaps between chunks of different levels
 
-fn generate_world_map(size: f32, seed: u32) -> WorldMap {
-    let mut world_map = island_stack(size as u32, seed);
-    climate_stack(&mut world_map, seed);
-    assign_biome(&mut world_map);
+/// Triangle mesh of the terrain that does not depend on the renderer, used by the app and the exporters
+#[derive(Debug, Clone, Default)]
+pub struct TerrainMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub colors: Vec<[f32; 4]>,
+    /// Counter-clockwise triangles seen from above
+    pub indices: Vec<u32>,
+}
+
+impl TerrainMesh {
+    /// Adds the vertices and triangles of `other`, moved by `offset`
+    pub fn append(&mut self, other: &TerrainMesh, offset: [f32; 3]) {
+        let first = self.positions.len() as u32;
+        self.positions.extend(other.positions.iter().map(|p| [p[0] + offset[0], p[1] + offset[1], p[2] + offset[2]]));
+        self.normals.extend_from_slice(&other.normals);
+        self.colors.extend_from_slice(&other.colors);
+        self.indices.extend(other.indices.iter().map(|i| i + first));
+    }
 
-    world_map
+    /// Smallest and largest position on each axis
+    pub fn bounds(&self) -> ([f32; 3], [f32; 3]) {
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for position in &self.positions {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(position[axis]);
+                max[axis] = max[axis].max(position[axis]);
+            }
+        }
+        (min, max)
+    }
 }
 
-pub fn generate_terrain_mesh(size: f32, subdivisions: u32, seed: u32) -> (Mesh, WorldMap) {
-    let mut mesh = Mesh::from(Plane3d::default().mesh().size(size, size).subdivisions(subdivisions));
+/// Height the exported terrain shows at a tile, lakes and rivers show their water surface
+pub fn surface_height(world_map: &WorldMap, x: usize, z: usize) -> f32 {
+    let height = world_map.get_height(x, z);
+    if height >= world_map.sea_level() {
+        world_map.get_water_level(x, z)
+    } else {
+        height
+    }
+}
 
-    // Generate the world map (heights, temperatures, etc.)
-    let mut world_map = generate_world_map(size, seed);
+/// Builds a grid of `subdivisions + 2` vertices per side centered on the origin, one unit per tile.
+/// Heights are taken from the nearest tile, lakes and rivers show their water surface.
+pub fn terrain_mesh(world_map: &WorldMap, subdivisions: u32) -> TerrainMesh {
     let world_size = world_map.get_size();
-    let mut colors = Vec::new();
+    let size = world_size as f32;
+    let vertices = subdivisions as usize + 2;
+    let spacing = size / (vertices - 1) as f32;
+
+    let mut mesh = TerrainMesh::default();
+    for row in 0..vertices {
+        for column in 0..vertices {
+            let x = -size / 2.0 + column as f32 * spacing;
+            let z = -size / 2.0 + row as f32 * spacing;
 
-    println!("Meshing and coloring...");
-    if let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION) {
-        for pos in positions.iter_mut() {
             // Normalize the vertex positions to map to the world map size
-            let normalized_x = (pos[0] / size + 0.5) * (world_size as f32 - 1.0);
-            let normalized_z = (pos[2] / size + 0.5) * (world_size as f32 - 1.0);
+            let normalized_x = (x / size + 0.5) * (world_size as f32 - 1.0);
+            let normalized_z = (z / size + 0.5) * (world_size as f32 - 1.0);
 
-            // Convert normalized positions to world map indices
-            let x_index = normalized_x.round() as usize;
-            let z_index = normalized_z.round() as usize;
+            // Convert normalized positions to world map indices, clamped to avoid out-of-bounds errors
+            let x_index = (normalized_x.round() as usize).clamp(0, world_size as usize - 1);
+            let z_index = (normalized_z.round() as usize).clamp(0, world_size as usize - 1);
 
-            // Clamp indices to avoid out-of-bounds errors
-            let x_index = x_index.clamp(0, world_size as usize - 1);
-            let z_index = z_index.clamp(0, world_size as usize - 1);
+            mesh.positions.push([x, surface_height(world_map, x_index, z_index), z]);
+            // Assign biome-based color
+            mesh.colors.push(world_map.get_biome(x_index, z_index).map_or([1.0; 4], |biome| biome.color()));
+        }
+    }
 
-            // Retrieve height from the world map
-            let height = world_map.get_height(x_index, z_index);
+    // Smooth normals from the height differences between the neighbouring vertices
+    let height = |column: usize, row: usize| mesh.positions[row * vertices + column][1];
+    let mut normals = Vec::with_capacity(vertices * vertices);
+    for row in 0..vertices {
+        for column in 0..vertices {
+            let (left, right) = (column.saturating_sub(1), (column + 1).min(vertices - 1));
+            let (up, down) = (row.saturating_sub(1), (row + 1).min(vertices - 1));
+            let dx = (height(right, row) - height(left, row)) / ((right - left) as f32 * spacing);
+            let dz = (height(column, down) - height(column, up)) / ((down - up) as f32 * spacing);
+            let length = (dx * dx + 1.0 + dz * dz).sqrt();
+            normals.push([-dx / length, 1.0 / length, -dz / length]);
+        }
+    }
+    mesh.normals = normals;
 
-            // Assign the height to the y-coordinate of the vertex
-            pos[1] = height;
+    for row in 0..vertices - 1 {
+        for column in 0..vertices - 1 {
+            let i = (row * vertices + column) as u32;
+            let below = i + vertices as u32;
+            mesh.indices.extend_from_slice(&[i, below, i + 1, i + 1, below, below + 1]);
+        }
+    }
+    mesh
+}
 
-            // Assign biome-based color
-            let biome_color = world_map.get_biome(x_index, z_index).unwrap_or(Biome::Grassland).color();
-            colors.push(biome_color);
+#[cfg(feature = "render")]
+impl From<TerrainMesh> for Mesh {
+    fn from(terrain: TerrainMesh) -> Self {
+        Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, terrain.positions)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, terrain.normals)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, terrain.colors)
+            .with_inserted_indices(Indices::U32(terrain.indices))
+    }
+}
 
+#[cfg(feature = "render")]
+impl TerrainMesh {
+    /// Copies a triangle list mesh of the app, vertices without normals or colors get defaults
+    pub fn from_mesh(mesh: &Mesh) -> Option<Self> {
+        let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {
+            return None;
+        };
+        let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+            Some(VertexAttributeValues::Float32x3(normals)) => normals.clone(),
+            _ => vec![[0.0, 1.0, 0.0]; positions.len()],
+        };
+        let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) => colors.clone(),
+            _ => vec![[1.0; 4]; positions.len()],
+        };
+        let indices = match mesh.indices() {
+            Some(indices) => indices.iter().map(|i| i as u32).collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        Some(TerrainMesh { positions: positions.clone(), normals, colors, indices })
+    }
+}
+
+/// Tunables of the chunked terrain shown in the app
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerrainMeshParams {
+    /// Width of a chunk in tiles
+    pub chunk_size: u32,
+    /// Tiles between neighbouring vertices of the most distant chunks, nearer chunks go down to 1 tile
+    pub tiles_per_vertex: u32,
+    /// Distance from the camera up to which chunks are meshed at full resolution, every doubling of it halves the resolution
+    pub lod_distance: f32,
+    /// Share of the distance a chunk has to move past a level boundary before its level changes
+    pub lod_hysteresis: f32,
+}
+
+impl Default for TerrainMeshParams {
+    fn default() -> Self {
+        TerrainMeshParams {
+            chunk_size: 512,
+            tiles_per_vertex: 16,
+            lod_distance: 256.0,
+            lod_hysteresis: 0.2,
+        }
+    }
+}
+
+impl TerrainMeshParams {
+    /// Coarsest level of detail, the one meshed with `tiles_per_vertex`
+    pub fn max_lod(&self) -> u32 {
+        self.tiles_per_vertex.max(1).next_power_of_two().trailing_zeros()
+    }
+
+    /// Tiles between neighbouring vertices at a level of detail, level 0 is full resolution
+    pub fn lod_step(&self, level: u32) -> usize {
+        (1usize << level).min(self.tiles_per_vertex.max(1) as usize)
+    }
+
+    /// Distance from which a level of detail is used
+    fn lod_start(&self, level: u32) -> f32 {
+        if level == 0 {
+            0.0
+        } else {
+            self.lod_distance * 2f32.powi(level as i32 - 1)
         }
+    }
 
-        // Assign vertex colors to the mesh
-        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    /// Level of detail of a chunk at `distance` from the camera that currently uses `current`.
+    /// The level only changes once the distance is past the boundary by the hysteresis, so chunks do not flicker between two levels.
+    pub fn lod_level(&self, distance: f32, current: u32) -> u32 {
+        let target = if distance < self.lod_distance {
+            0
+        } else {
+            ((distance / self.lod_distance).log2().floor() as u32 + 1).min(self.max_lod())
+        };
+        let coarser_held = target > current && distance < self.lod_start(current + 1) * (1.0 + self.lod_hysteresis);
+        let finer_held = target < current && distance > self.lod_start(current) * (1.0 - self.lod_hysteresis);
+        if coarser_held || finer_held {
+            current
+        } else {
+            target
+        }
     }
+}
 
-    println!("Recomputing normals");
-    mesh.compute_normals();
+/// Part of the terrain from tile (x, z) to tile (x + width, z + depth), both ends included.
+/// Neighbouring chunks share their edge tiles, so their meshes meet without cracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "render", derive(Component))]
+pub struct TerrainChunk {
+    pub x: usize,
+    pub z: usize,
+    pub width: usize,
+    pub depth: usize,
+}
 
-    (mesh, world_map)
-}
\ No newline at end of file
+impl TerrainChunk {
+    /// Whether the tile is part of the chunk, tiles on a shared edge are part of both chunks
+    pub fn contains(&self, x: usize, z: usize) -> bool {
+        (self.x..=self.x + self.width).contains(&x) && (self.z..=self.z + self.depth).contains(&z)
+    }
+
+    /// Chunks covering the world map, the last row and column are smaller if the size does not divide evenly
+    pub fn grid(world_map: &WorldMap, chunk_size: u32) -> Vec<TerrainChunk> {
+        let last = world_map.get_size() as usize - 1;
+        let chunk_size = chunk_size.max(1) as usize;
+        let mut chunks = Vec::new();
+        for x in (0..last).step_by(chunk_size) {
+            for z in (0..last).step_by(chunk_size) {
+                chunks.push(TerrainChunk { x, z, width: chunk_size.min(last - x), depth: chunk_size.min(last - z) });
+            }
+        }
+        chunks
+    }
+}
+
+/// Position of a tile in the app, one unit per tile with the world centered on the origin
+pub fn tile_position(world_map: &WorldMap, x: usize, z: usize) -> [f32; 2] {
+    let half = world_map.get_size() as f32 / 2.0;
+    [x as f32 - half, z as f32 - half]
+}
+
+/// Tiles from `start` to `end`, `step` apart and always ending at `end`
+pub(crate) fn samples(start: usize, end: usize, step: usize) -> Vec<usize> {
+    let mut samples: Vec<_> = (start..end).step_by(step).collect();
+    samples.push(end);
+    samples
+}
+
+/// Largest height difference between an edge at full resolution and the same edge with a vertex every `step` tiles
+fn edge_error(length: usize, step: usize, height: impl Fn(usize) -> f32) -> f32 {
+    let mut error = 0.0f32;
+    for pair in samples(0, length, step).windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+        let (height_a, height_b) = (height(a), height(b));
+        for t in a + 1..b {
+            let interpolated = height_a + (height_b - height_a) * (t - a) as f32 / (b - a) as f32;
+            error = error.max((height(t) - interpolated).abs());
+        }
+    }
+    error
+}
+
+/// Depth the skirts of a chunk need to cover the gap to a neighbour meshed with up to `coarsest_step` tiles between vertices
+pub fn skirt_depth(world_map: &WorldMap, chunk: &TerrainChunk, coarsest_step: usize) -> f32 {
+    let step = coarsest_step.max(1);
+    let (x1, z1) = (chunk.x + chunk.width, chunk.z + chunk.depth);
+    let error = edge_error(chunk.width, step, |t| world_map.get_height(chunk.x + t, chunk.z))
+        .max(edge_error(chunk.width, step, |t| world_map.get_height(chunk.x + t, z1)))
+        .max(edge_error(chunk.depth, step, |t| world_map.get_height(chunk.x, chunk.z + t)))
+        .max(edge_error(chunk.depth, step, |t| world_map.get_height(x1, chunk.z + t)));
+    // Keeps a little overlap on flat edges
+    error + 1.0
+}
+
+/// Mesh of a chunk with a vertex every `step` tiles, positions are relative to the first tile of the chunk.
+/// The ground is meshed under lakes and rivers too, `water::water_mesh` covers them.
+/// Edges shared with other chunks get skirts `skirt_depth` deep.
+pub fn chunk_mesh(world_map: &WorldMap, chunk: &TerrainChunk, step: usize, skirt_depth: f32) -> TerrainMesh {
+    let size = world_map.get_size() as usize;
+    let step = step.max(1);
+    let columns = samples(chunk.x, chunk.x + chunk.width, step);
+    let rows = samples(chunk.z, chunk.z + chunk.depth, step);
+
+    let mut mesh = TerrainMesh::default();
+    for &z in &rows {
+        for &x in &columns {
+            mesh.positions.push([(x - chunk.x) as f32, world_map.get_height(x, z), (z - chunk.z) as f32]);
+
+            // Slopes come from the world map instead of the chunk, so both sides of a shared edge get the same normal
+            let (left, right) = (x.saturating_sub(step), (x + step).min(size - 1));
+            let (up, down) = (z.saturating_sub(step), (z + step).min(size - 1));
+            let dx = (world_map.get_height(right, z) - world_map.get_height(left, z)) / (right - left) as f32;
+            let dz = (world_map.get_height(x, down) - world_map.get_height(x, up)) / (down - up) as f32;
+            let length = (dx * dx + 1.0 + dz * dz).sqrt();
+            mesh.normals.push([-dx / length, 1.0 / length, -dz / length]);
+
+            mesh.colors.push(world_map.get_biome(x, z).map_or([1.0; 4], |biome| biome.color()));
+        }
+    }
+
+    let vertices = columns.len();
+    for row in 0..rows.len() - 1 {
+        for column in 0..vertices - 1 {
+            let i = (row * vertices + column) as u32;
+            let below = i + vertices as u32;
+            mesh.indices.extend_from_slice(&[i, below, i + 1, i + 1, below, below + 1]);
+        }
+    }
+
+    // The world border has no neighbour to leave a gap to
+    let last_row = rows.len() - 1;
+    let last_column = vertices - 1;
+    let mut edges: Vec<Vec<usize>> = Vec::new();
+    if chunk.z > 0 {
+        edges.push((0..vertices).collect());
+    }
+    if chunk.z + chunk.depth < size - 1 {
+        edges.push((0..vertices).map(|column| last_row * vertices + column).collect());
+    }
+    if chunk.x > 0 {
+        edges.push((0..rows.len()).map(|row| row * vertices).collect());
+    }
+    if chunk.x + chunk.width < size - 1 {
+        edges.push((0..rows.len()).map(|row| row * vertices + last_column).collect());
+    }
+    for edge in edges {
+        let first_skirt = mesh.positions.len() as u32;
+        for &i in &edge {
+            let [x, y, z] = mesh.positions[i];
+            mesh.positions.push([x, y - skirt_depth, z]);
+            mesh.normals.push(mesh.normals[i]);
+            mesh.colors.push(mesh.colors[i]);
+        }
+        // Skirts are seen from either side depending on which chunk is coarser, so both windings are added
+        for k in 0..edge.len() - 1 {
+            let (a, b) = (edge[k] as u32, edge[k + 1] as u32);
+            let (skirt_a, skirt_b) = (first_skirt + k as u32, first_skirt + k as u32 + 1);
+            mesh.indices.extend_from_slice(&[a, skirt_a, b, b, skirt_a, skirt_b]);
+            mesh.indices.extend_from_slice(&[a, b, skirt_a, b, skirt_b, skirt_a]);
+        }
+    }
+    mesh
+}
+
+/// Meshes every chunk of the world map in parallel
+pub fn terrain_chunk_meshes(
+    world_map: &WorldMap,
+    params: &TerrainMeshParams,
+    progress: &Progress,
+) -> Result<Vec<(TerrainChunk, TerrainMesh)>, Cancelled> {
+    let chunks = TerrainChunk::grid(world_map, params.chunk_size);
+    let finished = AtomicUsize::new(0);
+    let meshes = chunks
+        .par_iter()
+        .map(|chunk| {
+            let step = params.lod_step(params.max_lod());
+            let mesh = chunk_mesh(world_map, chunk, step, skirt_depth(world_map, chunk, step));
+            let done = finished.fetch_add(1, Ordering::Relaxed) + 1;
+            // Cancellation is checked once all chunks are back
+            let _ = progress.update(done as f32 / chunks.len() as f32);
+            (*chunk, mesh)
+        })
+        .collect();
+    progress.check()?;
+    Ok(meshes)
+}
+
+/// Generates the world map and meshes its chunks
+pub fn generate_terrain(
+    settings: &GenerationSettings,
+    progress: &Progress,
+) -> Result<(Vec<(TerrainChunk, TerrainMesh)>, WorldMap), Cancelled> {
+    let world_map = generate_world_map_with_progress(settings, progress)?;
+    progress.begin("Meshing and coloring")?;
+    let chunks = terrain_chunk_meshes(&world_map, &settings.terrain_mesh, progress)?;
+    progress.finish();
+    Ok((chunks, world_map))
+}
@@ -1,5 +1,17 @@
 pub mod tile;
 pub mod islands;
+pub mod relief;
+pub mod erosion;
+pub mod thermal;
+pub mod hydrology;
 pub mod meshing;
+pub mod picking;
 pub mod biomes;
-pub mod climate;
\ No newline at end of file
+pub mod climate;
+pub mod pipeline;
+pub mod progress;
+pub mod chunks;
+pub mod save;
+pub mod settings;
+pub mod water;
+pub mod texturing;
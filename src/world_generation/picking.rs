@@ -0,0 +1,145 @@
+use super::tile::{MAX_HEIGHT, WorldMap};
+use super::water::water_surface;
+// Picking tiles by casting rays against the terrain
+// Rays are given in the space of the app, where tile (x, z) is at `meshing::tile_position` and one unit is one tile
+// The ray walks the grid cell by cell (DDA) and is tested against the two triangles of every cell it crosses,
+// the triangles are the same as the ones of a full resolution terrain mesh, raised to the water surface where there is water
+
+/// Where a ray hit the terrain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TerrainHit {
+    /// Tile closest to the hit point
+    pub tile: (usize, usize),
+    /// Hit point in app space
+    pub point: [f32; 3],
+    /// Distance from the ray origin to the hit point
+    pub distance: f32,
+}
+
+/// How far outside a triangle, in barycentric coordinates, a ray still hits it
+const EDGE_TOLERANCE: f64 = 1e-9;
+
+/// Distance along the ray to a triangle, if the ray hits it from either side
+fn intersect_triangle(origin: [f64; 3], direction: [f64; 3], triangle: [[f64; 3]; 3]) -> Option<f64> {
+    let sub = |a: [f64; 3], b: [f64; 3]| [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+    let cross = |a: [f64; 3], b: [f64; 3]| [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+    let dot = |a: [f64; 3], b: [f64; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+
+    // Möller–Trumbore, with a little slack on the edges so rays through shared edges and vertices hit one of the triangles
+    let edge1 = sub(triangle[1], triangle[0]);
+    let edge2 = sub(triangle[2], triangle[0]);
+    let p = cross(direction, edge2);
+    let determinant = dot(edge1, p);
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+    let to_origin = sub(origin, triangle[0]);
+    let u = dot(to_origin, p) / determinant;
+    if !(-EDGE_TOLERANCE..=1.0 + EDGE_TOLERANCE).contains(&u) {
+        return None;
+    }
+    let q = cross(to_origin, edge1);
+    let v = dot(direction, q) / determinant;
+    if v < -EDGE_TOLERANCE || u + v > 1.0 + EDGE_TOLERANCE {
+        return None;
+    }
+    let t = dot(edge2, q) / determinant;
+    (t >= 0.0).then_some(t)
+}
+
+/// Distance along the ray to the surface of the cell between tiles (x, z) and (x + 1, z + 1)
+fn intersect_cell(world_map: &WorldMap, x: usize, z: usize, origin: [f64; 3], direction: [f64; 3]) -> Option<f64> {
+    let height = |x: usize, z: usize| water_surface(world_map, x, z).unwrap_or_else(|| world_map.get_height(x, z));
+    let vertex = |x: usize, z: usize| [x as f64, height(x, z) as f64, z as f64];
+    let (top_left, top_right) = (vertex(x, z), vertex(x + 1, z));
+    let (bottom_left, bottom_right) = (vertex(x, z + 1), vertex(x + 1, z + 1));
+    // Split along the same diagonal as the terrain mesh
+    let first = intersect_triangle(origin, direction, [top_left, bottom_left, top_right]);
+    let second = intersect_triangle(origin, direction, [top_right, bottom_left, bottom_right]);
+    match (first, second) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (hit, None) | (None, hit) => hit,
+    }
+}
+
+/// Casts a ray in app space against the terrain, returns the first hit
+pub fn raycast(world_map: &WorldMap, origin: [f32; 3], direction: [f32; 3]) -> Option<TerrainHit> {
+    let size = world_map.get_size() as usize;
+    if size < 2 {
+        return None;
+    }
+    let half = size as f64 / 2.0;
+
+    // Grid space, tile (x, z) is at (x, z)
+    let origin = [origin[0] as f64 + half, origin[1] as f64, origin[2] as f64 + half];
+    let length = direction.iter().map(|&d| (d as f64).powi(2)).sum::<f64>().sqrt();
+    if length == 0.0 {
+        return None;
+    }
+    let direction = direction.map(|d| d as f64 / length);
+
+    // Clip the ray to the box the terrain lies in
+    let low = [0.0, 0.0, 0.0];
+    let high = [(size - 1) as f64, MAX_HEIGHT as f64 + 1.0, (size - 1) as f64];
+    let (mut t_enter, mut t_exit) = (0.0f64, f64::INFINITY);
+    for axis in 0..3 {
+        if direction[axis] == 0.0 {
+            if origin[axis] < low[axis] || origin[axis] > high[axis] {
+                return None;
+            }
+        } else {
+            let t0 = (low[axis] - origin[axis]) / direction[axis];
+            let t1 = (high[axis] - origin[axis]) / direction[axis];
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+    }
+    if t_enter > t_exit {
+        return None;
+    }
+
+    // Walk the cells the ray crosses from where it enters the box
+    let last_cell = size as isize - 2;
+    let entry = [origin[0] + direction[0] * t_enter, origin[2] + direction[2] * t_enter];
+    let mut cell = [
+        (entry[0].floor() as isize).clamp(0, last_cell),
+        (entry[1].floor() as isize).clamp(0, last_cell),
+    ];
+    let mut step = [0isize; 2];
+    let mut t_next = [f64::INFINITY; 2];
+    let mut t_delta = [f64::INFINITY; 2];
+    for (i, axis) in [0, 2].into_iter().enumerate() {
+        if direction[axis] > 0.0 {
+            step[i] = 1;
+            t_next[i] = ((cell[i] + 1) as f64 - origin[axis]) / direction[axis];
+            t_delta[i] = 1.0 / direction[axis];
+        } else if direction[axis] < 0.0 {
+            step[i] = -1;
+            t_next[i] = (cell[i] as f64 - origin[axis]) / direction[axis];
+            t_delta[i] = -1.0 / direction[axis];
+        }
+    }
+
+    loop {
+        if let Some(t) = intersect_cell(world_map, cell[0] as usize, cell[1] as usize, origin, direction) {
+            let point = [origin[0] + direction[0] * t, origin[1] + direction[1] * t, origin[2] + direction[2] * t];
+            let tile_x = (point[0].round() as usize).min(size - 1);
+            let tile_z = (point[2].round() as usize).min(size - 1);
+            return Some(TerrainHit {
+                tile: (tile_x, tile_z),
+                point: [(point[0] - half) as f32, point[1] as f32, (point[2] - half) as f32],
+                distance: t as f32,
+            });
+        }
+
+        let i = if t_next[0] < t_next[1] { 0 } else { 1 };
+        if t_next[i] > t_exit {
+            return None;
+        }
+        cell[i] += step[i];
+        t_next[i] += t_delta[i];
+        if cell[i] < 0 || cell[i] > last_cell {
+            return None;
+        }
+    }
+}
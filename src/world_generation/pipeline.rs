@@ -0,0 +1,42 @@
+use super::biomes::assign_biome;
+use super::islands::island_stack;
+use super::relief::relief_stack;
+use super::erosion::erosion_stack;
+use super::thermal::thermal_stack;
+use super::hydrology::hydrology_stack;
+use super::climate::climate_stack;
+use super::progress::{Cancelled, Progress};
+use super::settings::GenerationSettings;
+use super::tile::WorldMap;
+
+/// Runs every generation stage over the world map, in order
+pub fn generate(world_map: &mut WorldMap, settings: &GenerationSettings) {
+    generate_with_progress(world_map, settings, &Progress::default()).expect("generation without a cancellation token is never cancelled");
+}
+
+/// Runs every generation stage over the world map, reporting to `progress` and stopping once it is cancelled.
+/// A cancelled world map is only partly generated.
+pub fn generate_with_progress(world_map: &mut WorldMap, settings: &GenerationSettings, progress: &Progress) -> Result<(), Cancelled> {
+    world_map.set_sea_level(settings.sea_level);
+    island_stack(world_map, settings, progress)?;
+    relief_stack(world_map, settings, progress)?;
+    erosion_stack(world_map, settings, progress)?;
+    thermal_stack(world_map, settings, progress)?;
+    hydrology_stack(world_map, settings, progress)?;
+    climate_stack(world_map, settings, progress)?;
+    assign_biome(world_map, progress)?;
+    progress.finish();
+    Ok(())
+}
+
+pub fn generate_world_map(settings: &GenerationSettings) -> WorldMap {
+    let mut world_map = WorldMap::new(settings.world_size);
+    generate(&mut world_map, settings);
+    world_map
+}
+
+pub fn generate_world_map_with_progress(settings: &GenerationSettings, progress: &Progress) -> Result<WorldMap, Cancelled> {
+    let mut world_map = WorldMap::new(settings.world_size);
+    generate_with_progress(&mut world_map, settings, progress)?;
+    Ok(world_map)
+}
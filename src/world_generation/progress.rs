@@ -0,0 +1,161 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+// Progress reporting and cancellation for long running generation
+// Stages announce themselves with `begin`, report how far they are with `update` and stop early once cancelled
+// Updates are passed to a callback, so the CLI can draw a progress bar and the app can show it in its UI
+
+/// Shared flag to stop a running generation, clones refer to the same flag
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Returned by stages that stopped because their generation was cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "generation was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// State of the running stage, passed to the progress callback
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressUpdate {
+    pub stage: &'static str,
+    /// Share of the stage that is done, from 0.0 to 1.0
+    pub fraction: f32,
+    /// Time since the stage began
+    pub stage_elapsed: Duration,
+    /// Time since the progress was created
+    pub elapsed: Duration,
+}
+
+struct Stage {
+    name: &'static str,
+    started: Instant,
+    /// Last fraction passed to the callback
+    reported: f32,
+}
+
+/// Smallest change of the fraction passed to the callback, so tight loops can update freely
+const REPORT_STEP: f32 = 0.01;
+
+type Callback = Box<dyn Fn(&ProgressUpdate) + Send + Sync>;
+
+/// Collects the progress of the stages of one generation
+pub struct Progress {
+    callback: Option<Callback>,
+    cancellation: CancellationToken,
+    started: Instant,
+    stage: Mutex<Option<Stage>>,
+}
+
+impl Default for Progress {
+    /// Reports nothing and is never cancelled
+    fn default() -> Self {
+        Progress {
+            callback: None,
+            cancellation: CancellationToken::new(),
+            started: Instant::now(),
+            stage: Mutex::new(None),
+        }
+    }
+}
+
+impl Progress {
+    pub fn new(callback: impl Fn(&ProgressUpdate) + Send + Sync + 'static) -> Self {
+        Progress { callback: Some(Box::new(callback)), ..Self::default() }
+    }
+
+    /// Prints the name of every stage as it begins
+    pub fn printing() -> Self {
+        Self::new(|update| {
+            if update.fraction == 0.0 {
+                println!("{}...", update.stage);
+            }
+        })
+    }
+
+    /// Stops the stages once `token` is cancelled
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Fails if the generation was cancelled, stages call it between steps
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Finishes the running stage and begins the next one
+    pub fn begin(&self, name: &'static str) -> Result<(), Cancelled> {
+        self.finish();
+        self.check()?;
+        let mut stage = self.stage.lock().unwrap();
+        *stage = Some(Stage { name, started: Instant::now(), reported: 0.0 });
+        self.report(stage.as_ref().unwrap(), 0.0);
+        Ok(())
+    }
+
+    /// Reports how much of the running stage is done, from 0.0 to 1.0
+    pub fn update(&self, fraction: f32) -> Result<(), Cancelled> {
+        let fraction = fraction.clamp(0.0, 1.0);
+        if let Some(stage) = self.stage.lock().unwrap().as_mut() {
+            if fraction >= stage.reported + REPORT_STEP || (fraction == 1.0 && stage.reported < 1.0) {
+                stage.reported = fraction;
+                self.report(stage, fraction);
+            }
+        }
+        self.check()
+    }
+
+    /// Reports the running stage as done
+    pub fn finish(&self) {
+        if let Some(stage) = self.stage.lock().unwrap().take() {
+            if stage.reported < 1.0 {
+                self.report(&stage, 1.0);
+            }
+        }
+    }
+
+    fn report(&self, stage: &Stage, fraction: f32) {
+        if let Some(callback) = &self.callback {
+            callback(&ProgressUpdate {
+                stage: stage.name,
+                fraction,
+                stage_elapsed: stage.started.elapsed(),
+                elapsed: self.started.elapsed(),
+            });
+        }
+    }
+}
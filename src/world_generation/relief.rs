@@ -0,0 +1,140 @@
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin, RidgedMulti};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use super::progress::{Cancelled, Progress};
+use super::settings::GenerationSettings;
+use super::tile::{MAX_HEIGHT, WorldMap, noise_coords};
+// This file turns the flat island mask into continuous terrain
+// Land rises from the sea level up to MAX_HEIGHT
+// The sea floor rises towards the coast from the ocean heights of the island stage
+
+/// Tunables of the relief stage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReliefParams {
+    /// Octaves used for the hills and the mountain ridges
+    pub octaves: usize,
+    /// Number of hills across the world
+    pub hills_frequency: f64,
+    /// Number of mountain ridges across the world
+    pub ridges_frequency: f64,
+    /// Number of mountain ranges across the world
+    pub mountains_frequency: f64,
+    /// Share of the land relief that comes from rolling hills, the rest comes from mountains
+    pub hills_weight: f32,
+    /// Distance in tiles over which land rises from the coast and the sea floor rises to the coast
+    pub coast_radius: usize,
+    /// Height variation of the sea floor
+    pub sea_floor_roughness: f32,
+    /// Minimum depth of water tiles, keeps the coastline where the island mask put it
+    pub min_depth: f32,
+}
+
+impl Default for ReliefParams {
+    fn default() -> Self {
+        ReliefParams {
+            octaves: 6,
+            hills_frequency: 32.0,
+            ridges_frequency: 12.0,
+            mountains_frequency: 4.0,
+            hills_weight: 0.25,
+            coast_radius: 96,
+            sea_floor_roughness: 4.0,
+            min_depth: 1.0,
+        }
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, value: f32) -> f32 {
+    let t = ((value - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Maps noise output from -1.0 - 1.0 to 0.0 - 1.0
+fn unit(value: f64) -> f32 {
+    ((value + 1.0) / 2.0).clamp(0.0, 1.0) as f32
+}
+
+fn transpose(plane: &[f32], size: usize) -> Vec<f32> {
+    let mut transposed = vec![0.0; plane.len()];
+    transposed.par_chunks_mut(size).enumerate().for_each(|(z, row)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = plane[x * size + z];
+        }
+    });
+    transposed
+}
+
+/// Averages every row of the plane over a window of `2 * radius + 1` values
+fn blur_rows(plane: &mut [f32], size: usize, radius: usize) {
+    plane.par_chunks_mut(size).for_each(|row| {
+        let mut prefix = Vec::with_capacity(size + 1);
+        prefix.push(0.0f64);
+        for value in row.iter() {
+            prefix.push(prefix[prefix.len() - 1] + *value as f64);
+        }
+        for (i, value) in row.iter_mut().enumerate() {
+            let start = i.saturating_sub(radius);
+            let end = (i + radius + 1).min(size);
+            *value = ((prefix[end] - prefix[start]) / (end - start) as f64) as f32;
+        }
+    });
+}
+
+/// Separable box blur of a square plane stored like the `WorldMap` layers
+fn box_blur(plane: &mut Vec<f32>, size: usize, radius: usize) {
+    blur_rows(plane, size, radius);
+    let mut transposed = transpose(plane, size);
+    blur_rows(&mut transposed, size, radius);
+    *plane = transpose(&transposed, size);
+}
+
+/// Replaces the flat island heights with hills, mountain ranges and a sloped sea floor
+pub fn generate_relief(world_map: &mut WorldMap, seed: u32, world_size: u32, params: &ReliefParams) {
+    let size = world_map.get_size() as usize;
+    let origin = world_map.origin();
+    let sea_level = world_map.sea_level();
+
+    // Blurring the land mask gives the distance to the coast, blurring the heights smooths the ocean chunks
+    let mut land: Vec<f32> = world_map.heights.par_iter().map(|&height| if height >= sea_level { 1.0 } else { 0.0 }).collect();
+    let mut floor: Vec<f32> = world_map.heights.par_iter().map(|&height| height.min(sea_level)).collect();
+    box_blur(&mut land, size, params.coast_radius);
+    box_blur(&mut floor, size, params.coast_radius);
+
+    let hills = Fbm::<Perlin>::new(seed.wrapping_add(2)).set_octaves(params.octaves);
+    let ridges = RidgedMulti::<Perlin>::new(seed.wrapping_add(3)).set_octaves(params.octaves);
+    let mountains = Perlin::new(seed.wrapping_add(4));
+    let sea_floor = Fbm::<Perlin>::new(seed.wrapping_add(5)).set_octaves(3);
+
+    world_map.heights.par_chunks_mut(size).enumerate().for_each(|(x, row)| {
+        for (z, height) in row.iter_mut().enumerate() {
+            let i = x * size + z;
+            let [nx, nz] = noise_coords(origin, world_size, x, z);
+
+            if *height >= sea_level {
+                // 0.0 on the coastline, 1.0 once we are `coast_radius` tiles inland
+                let falloff = smoothstep(0.5, 1.0, land[i]);
+
+                let hill = unit(hills.get([nx * params.hills_frequency, nz * params.hills_frequency]));
+                let ridge = unit(ridges.get([nx * params.ridges_frequency, nz * params.ridges_frequency]));
+                let mountain = smoothstep(0.0, 0.5, mountains.get([nx * params.mountains_frequency, nz * params.mountains_frequency]) as f32);
+
+                let relief = params.hills_weight * hill + (1.0 - params.hills_weight) * ridge * mountain;
+                *height = sea_level + falloff * relief * (MAX_HEIGHT - sea_level);
+            } else {
+                // 1.0 on the coastline, 0.0 once we are `coast_radius` tiles out at sea
+                let shelf = smoothstep(0.0, 0.5, land[i]);
+                let bumps = sea_floor.get([nx * params.hills_frequency, nz * params.hills_frequency]) as f32 * params.sea_floor_roughness;
+
+                let depth = floor[i] + (sea_level - floor[i]) * shelf;
+                *height = (depth + bumps).clamp(0.0, sea_level - params.min_depth);
+            }
+        }
+    });
+}
+
+pub fn relief_stack(world_map: &mut WorldMap, settings: &GenerationSettings, progress: &Progress) -> Result<(), Cancelled> {
+    progress.begin("Generating relief")?;
+    generate_relief(world_map, settings.seed, settings.world_size, &settings.relief);
+    Ok(())
+}
@@ -0,0 +1,369 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use rayon::prelude::*;
+use super::biomes::BiomeRegistry;
+use super::settings::{GenerationSettings, SettingsError};
+use super::tile::{Biome, NO_BIOME, WorldMap};
+// Binary world files
+// Layout, all numbers little-endian:
+//   magic "TGWORLD\0", version u16, size u32, origin i64 i64, seed u32, sea level f32
+//   generator and RON generation settings as u32 length + UTF-8
+//   biome names as u16 count, then u16 length + UTF-8 each, biome IDs in the file index this list
+//   layers as u16 count, then tag [u8; 4], u64 compressed length and the zlib compressed plane each
+// Temperature and rainfall are quantized u16 planes, see `WorldMap::temperatures`
+// Sediment, water flux and flow are only written if the map keeps them
+// Planes of multi-byte values are stored byte-shuffled (all first bytes, then all second bytes, ...) which compresses a lot better
+
+const MAGIC: &[u8; 8] = b"TGWORLD\0";
+pub const VERSION: u16 = 1;
+
+/// Largest world size a file may have, a corrupt size must not make loading allocate all memory
+const MAX_SIZE: u32 = 32768;
+
+/// Layers every world file has
+const REQUIRED_LAYERS: [&[u8; 4]; 6] = [b"HGHT", b"TEMP", b"RAIN", b"BIOM", b"WATR", b"RIVR"];
+
+/// Version of the generator that wrote the file
+const GENERATOR: &str = concat!("terrain-generation ", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    NotAWorldFile,
+    UnsupportedVersion(u16),
+    Corrupt(String),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(err) => write!(f, "could not access world file: {}", err),
+            SaveError::NotAWorldFile => write!(f, "not a world file"),
+            SaveError::UnsupportedVersion(version) => write!(f, "world file version {} is not supported, expected {}", version, VERSION),
+            SaveError::Corrupt(message) => write!(f, "world file is corrupt: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<io::Error> for SaveError {
+    fn from(err: io::Error) -> Self {
+        SaveError::Io(err)
+    }
+}
+
+/// A world read back from a file together with how it was generated
+pub struct SavedWorld {
+    pub world_map: WorldMap,
+    pub seed: u32,
+    pub generator: String,
+    /// Generation settings as RON
+    pub settings: String,
+}
+
+impl SavedWorld {
+    pub fn generation_settings(&self) -> Result<GenerationSettings, SettingsError> {
+        GenerationSettings::from_ron(&self.settings)
+    }
+}
+
+/// Byte-shuffles values given as their little-endian bytes
+fn shuffle<const N: usize>(values: impl ExactSizeIterator<Item = [u8; N]>) -> Vec<u8> {
+    let len = values.len();
+    let mut bytes = vec![0u8; len * N];
+    for (i, value) in values.enumerate() {
+        for (b, byte) in value.into_iter().enumerate() {
+            bytes[b * len + i] = byte;
+        }
+    }
+    bytes
+}
+
+fn unshuffle<const N: usize>(bytes: &[u8]) -> impl Iterator<Item = [u8; N]> + '_ {
+    let len = bytes.len() / N;
+    (0..len).map(move |i| std::array::from_fn(|b| bytes[b * len + i]))
+}
+
+fn shuffle_f32(plane: &[f32]) -> Vec<u8> {
+    shuffle(plane.iter().map(|value| value.to_le_bytes()))
+}
+
+fn unshuffle_f32(bytes: &[u8]) -> Vec<f32> {
+    unshuffle(bytes).map(f32::from_le_bytes).collect()
+}
+
+fn unshuffle_u16(bytes: &[u8]) -> Vec<u16> {
+    unshuffle(bytes).map(u16::from_le_bytes).collect()
+}
+
+/// Bytes per tile of a layer, `None` for layers this version does not know
+fn element_size(tag: &[u8; 4]) -> Option<usize> {
+    match tag {
+        b"BIOM" | b"RIVR" => Some(1),
+        b"TEMP" | b"RAIN" => Some(2),
+        b"HGHT" | b"WATR" | b"SEDI" | b"FLUX" | b"FLOW" => Some(4),
+        _ => None,
+    }
+}
+
+/// Raw bytes of every layer with its tag
+fn layer_bytes(world_map: &WorldMap) -> Vec<([u8; 4], Vec<u8>)> {
+    let mut layers = vec![
+        (*b"HGHT", shuffle_f32(&world_map.heights)),
+        (*b"TEMP", shuffle(world_map.temperatures.iter().map(|value| value.to_le_bytes()))),
+        (*b"RAIN", shuffle(world_map.rainfall.iter().map(|value| value.to_le_bytes()))),
+        (*b"BIOM", world_map.biomes.clone()),
+        (*b"WATR", shuffle_f32(&world_map.water_levels)),
+        (*b"RIVR", world_map.rivers.iter().map(|&river| river as u8).collect()),
+    ];
+    let optional = [(*b"SEDI", &world_map.sediment), (*b"FLUX", &world_map.water_flux), (*b"FLOW", &world_map.flows)];
+    for (tag, plane) in optional {
+        if let Some(plane) = plane {
+            layers.push((tag, shuffle_f32(plane)));
+        }
+    }
+    layers
+}
+
+/// Writes the world map with the settings it was generated with
+pub fn save_world(world_map: &WorldMap, settings: &GenerationSettings, path: &Path) -> io::Result<()> {
+    // Layers are compressed in parallel, that is where nearly all of the time goes
+    let layers: Vec<([u8; 4], Vec<u8>)> = layer_bytes(world_map)
+        .into_par_iter()
+        .map(|(tag, bytes)| {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&bytes)?;
+            Ok((tag, encoder.finish()?))
+        })
+        .collect::<io::Result<_>>()?;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&world_map.get_size().to_le_bytes())?;
+    let (origin_x, origin_z) = world_map.origin();
+    writer.write_all(&origin_x.to_le_bytes())?;
+    writer.write_all(&origin_z.to_le_bytes())?;
+    writer.write_all(&settings.seed.to_le_bytes())?;
+    writer.write_all(&world_map.sea_level().to_le_bytes())?;
+    write_string(&mut writer, GENERATOR)?;
+    write_string(&mut writer, &settings.to_ron())?;
+
+    let registry = BiomeRegistry::global();
+    writer.write_all(&(registry.iter().count() as u16).to_le_bytes())?;
+    for (_, biome) in registry.iter() {
+        let len = u16::try_from(biome.name.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("biome name {} is too long", biome.name)))?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(biome.name.as_bytes())?;
+    }
+
+    writer.write_all(&(layers.len() as u16).to_le_bytes())?;
+    for (tag, data) in layers {
+        writer.write_all(&tag)?;
+        writer.write_all(&(data.len() as u64).to_le_bytes())?;
+        writer.write_all(&data)?;
+    }
+    writer.flush()
+}
+
+fn write_string(writer: &mut impl Write, value: &str) -> io::Result<()> {
+    writer.write_all(&(value.len() as u32).to_le_bytes())?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_bytes(reader: &mut impl Read, len: usize) -> Result<Vec<u8>, SaveError> {
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(SaveError::Corrupt("unexpected end of file".to_string()));
+    }
+    Ok(bytes)
+}
+
+fn read_string(reader: &mut impl Read, len: usize) -> Result<String, SaveError> {
+    String::from_utf8(read_bytes(reader, len)?).map_err(|_| SaveError::Corrupt("invalid text".to_string()))
+}
+
+/// Reads a world written by `save_world`.
+/// Biomes are matched by name, biomes missing from the current biome table become `NO_BIOME`.
+pub fn load_world(path: &Path) -> Result<SavedWorld, SaveError> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    if &read_array::<8>(&mut reader)? != MAGIC {
+        return Err(SaveError::NotAWorldFile);
+    }
+    let version = u16::from_le_bytes(read_array(&mut reader)?);
+    if version != VERSION {
+        return Err(SaveError::UnsupportedVersion(version));
+    }
+
+    let size = u32::from_le_bytes(read_array(&mut reader)?);
+    if !(2..=MAX_SIZE).contains(&size) {
+        return Err(SaveError::Corrupt(format!("world size {} is outside of 2 - {}", size, MAX_SIZE)));
+    }
+    let origin_x = i64::from_le_bytes(read_array(&mut reader)?);
+    let origin_z = i64::from_le_bytes(read_array(&mut reader)?);
+    let seed = u32::from_le_bytes(read_array(&mut reader)?);
+    let sea_level = f32::from_le_bytes(read_array(&mut reader)?);
+    let len = u32::from_le_bytes(read_array(&mut reader)?) as usize;
+    let generator = read_string(&mut reader, len)?;
+    let len = u32::from_le_bytes(read_array(&mut reader)?) as usize;
+    let settings = read_string(&mut reader, len)?;
+
+    let registry = BiomeRegistry::global();
+    let biome_count = u16::from_le_bytes(read_array(&mut reader)?);
+    let mut biome_ids = [NO_BIOME; 256];
+    for id in 0..biome_count as usize {
+        let len = u16::from_le_bytes(read_array(&mut reader)?) as usize;
+        let name = read_string(&mut reader, len)?;
+        if let (Some(slot), Some(Biome(current))) = (biome_ids.get_mut(id), registry.find(&name)) {
+            *slot = current;
+        }
+    }
+
+    let layer_count = u16::from_le_bytes(read_array(&mut reader)?);
+    let mut layers = Vec::with_capacity(layer_count as usize);
+    for _ in 0..layer_count {
+        let tag = read_array::<4>(&mut reader)?;
+        let len = u64::from_le_bytes(read_array(&mut reader)?) as usize;
+        layers.push((tag, read_bytes(&mut reader, len)?));
+    }
+
+    if let Some(missing) = REQUIRED_LAYERS.iter().find(|&&required| !layers.iter().any(|(tag, _)| tag == required)) {
+        return Err(SaveError::Corrupt(format!("layer {} is missing", String::from_utf8_lossy(*missing))));
+    }
+
+    // Unknown layers are skipped, the others may not decompress to more than their plane
+    let tiles = size as usize * size as usize;
+    let layers: Vec<([u8; 4], Vec<u8>)> = layers
+        .into_par_iter()
+        .filter_map(|(tag, data)| element_size(&tag).map(|element_size| (tag, data, tiles * element_size)))
+        .map(|(tag, data, len)| {
+            let mut bytes = Vec::new();
+            ZlibDecoder::new(&data[..]).take(len as u64 + 1).read_to_end(&mut bytes)?;
+            if bytes.len() != len {
+                return Err(SaveError::Corrupt(format!("layer {} has the wrong size", String::from_utf8_lossy(&tag))));
+            }
+            Ok((tag, bytes))
+        })
+        .collect::<Result<_, SaveError>>()?;
+
+    let mut world_map = WorldMap::with_origin(size, (origin_x, origin_z));
+    world_map.set_sea_level(sea_level);
+    for (tag, bytes) in layers {
+        match &tag {
+            b"HGHT" => world_map.heights = unshuffle_f32(&bytes),
+            b"TEMP" => world_map.temperatures = unshuffle_u16(&bytes),
+            b"RAIN" => world_map.rainfall = unshuffle_u16(&bytes),
+            b"BIOM" => world_map.biomes = bytes.iter().map(|&id| biome_ids[id as usize]).collect(),
+            b"SEDI" => world_map.sediment = Some(unshuffle_f32(&bytes)),
+            b"FLUX" => world_map.water_flux = Some(unshuffle_f32(&bytes)),
+            b"WATR" => world_map.water_levels = unshuffle_f32(&bytes),
+            b"RIVR" => world_map.rivers = bytes.iter().map(|&river| river != 0).collect(),
+            b"FLOW" => world_map.flows = Some(unshuffle_f32(&bytes)),
+            _ => {}
+        }
+    }
+
+    Ok(SavedWorld { world_map, seed, generator, settings })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A world with a different value in every tile of every layer
+    fn varied_world() -> WorldMap {
+        let size = 9;
+        let biome_count = BiomeRegistry::global().iter().count();
+        let mut world_map = WorldMap::with_origin(size, (-18, 27));
+        world_map.set_sea_level(55.5);
+        for i in 0..(size * size) as usize {
+            world_map.heights[i] = i as f32 * 1.5 - 20.0;
+            world_map.temperatures[i] = (i * 811) as u16;
+            world_map.rainfall[i] = (i * 499) as u16;
+            world_map.biomes[i] = if i % 10 == 0 { NO_BIOME } else { (i % biome_count) as u8 };
+            world_map.water_levels[i] = world_map.heights[i].max(55.5);
+            world_map.rivers[i] = i % 3 == 0;
+        }
+        world_map.sediment = Some((0..size * size).map(|i| i as f32 * -0.25).collect());
+        world_map.flows = Some((0..size * size).map(|i| i as f32 + 1.0).collect());
+        world_map
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("terrain-save-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn loads_what_was_saved() {
+        let world_map = varied_world();
+        let settings = GenerationSettings { seed: 1234, ..Default::default() };
+        let path = temp_file("round-trip.world");
+        save_world(&world_map, &settings, &path).unwrap();
+        let saved = load_world(&path);
+        std::fs::remove_file(&path).unwrap();
+        let saved = saved.unwrap();
+
+        let loaded = &saved.world_map;
+        assert_eq!(saved.seed, 1234);
+        assert_eq!(saved.generator, GENERATOR);
+        assert_eq!(saved.generation_settings().unwrap().to_ron(), settings.to_ron());
+        assert_eq!(loaded.get_size(), world_map.get_size());
+        assert_eq!(loaded.origin(), world_map.origin());
+        assert_eq!(loaded.sea_level(), world_map.sea_level());
+        assert_eq!(loaded.heights, world_map.heights);
+        assert_eq!(loaded.temperatures, world_map.temperatures);
+        assert_eq!(loaded.rainfall, world_map.rainfall);
+        assert_eq!(loaded.biomes, world_map.biomes);
+        assert_eq!(loaded.water_levels, world_map.water_levels);
+        assert_eq!(loaded.rivers, world_map.rivers);
+        assert_eq!(loaded.sediment, world_map.sediment);
+        assert_eq!(loaded.water_flux, None);
+        assert_eq!(loaded.flows, world_map.flows);
+    }
+
+    #[test]
+    fn rejects_corrupt_files() {
+        let path = temp_file("corrupt.world");
+        save_world(&varied_world(), &GenerationSettings::default(), &path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let load = |bytes: &[u8]| {
+            std::fs::write(&path, bytes).unwrap();
+            load_world(&path)
+        };
+
+        // The size right after the magic and version
+        let mut huge = bytes.clone();
+        huge[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        let too_large = load(&huge);
+
+        let heights = bytes.windows(4).position(|tag| tag == b"HGHT").unwrap();
+        let mut missing = bytes.clone();
+        missing[heights..heights + 4].copy_from_slice(b"XXXX");
+        let missing = load(&missing);
+
+        let truncated = load(&bytes[..bytes.len() - 10]);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(too_large, Err(SaveError::Corrupt(_))));
+        assert!(matches!(missing, Err(SaveError::Corrupt(message)) if message.contains("HGHT")));
+        assert!(matches!(truncated, Err(SaveError::Corrupt(_))));
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+#[cfg(feature = "render")]
+use bevy::ecs::system::Resource;
+use serde::{Deserialize, Serialize};
+use super::chunks::ChunkParams;
+use super::climate::{RainfallParams, TemperatureParams};
+use super::erosion::ErosionParams;
+use super::hydrology::HydrologyParams;
+use super::islands::IslandParams;
+use super::meshing::TerrainMeshParams;
+use super::relief::ReliefParams;
+use super::texturing::TexturingParams;
+use super::thermal::ThermalParams;
+use super::tile::{MAX_HEIGHT, SEA_LEVEL};
+use super::water::WaterParams;
+// Every tunable of the generator in one place
+// Presets are stored as RON, fields missing from a file keep their defaults so old presets keep loading
+
+/// Default location of the generation settings, relative to the working directory
+pub const SETTINGS_PATH: &str = "resources/settings.ron";
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(std::io::Error),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Io(err) => write!(f, "could not read settings: {}", err),
+            SettingsError::Parse(message) => write!(f, "could not parse settings: {}", message),
+            SettingsError::Invalid(message) => write!(f, "invalid settings: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+impl From<std::io::Error> for SettingsError {
+    fn from(err: std::io::Error) -> Self {
+        SettingsError::Io(err)
+    }
+}
+
+/// Settings of the whole generation pipeline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "render", derive(Resource))]
+#[serde(default)]
+pub struct GenerationSettings {
+    pub seed: u32,
+    /// Width of the world in tiles, noise and latitude are scaled to it.
+    /// Chunks can still be generated anywhere, see `chunks::ChunkedWorld`.
+    pub world_size: u32,
+    /// Vertices between the corners of the exported terrain mesh
+    pub subdivisions: u32,
+    /// Height below which tiles are ocean
+    pub sea_level: f32,
+    pub islands: IslandParams,
+    pub relief: ReliefParams,
+    pub erosion: ErosionParams,
+    pub thermal: ThermalParams,
+    pub hydrology: HydrologyParams,
+    pub temperature: TemperatureParams,
+    pub rainfall: RainfallParams,
+    pub chunks: ChunkParams,
+    pub terrain_mesh: TerrainMeshParams,
+    pub water: WaterParams,
+    pub texturing: TexturingParams,
+}
+
+impl Default for GenerationSettings {
+    fn default() -> Self {
+        GenerationSettings {
+            seed: 1,
+            // 4 chunks of 4096
+            world_size: 4 * 4096,
+            subdivisions: 1000,
+            sea_level: SEA_LEVEL,
+            islands: IslandParams::default(),
+            relief: ReliefParams::default(),
+            erosion: ErosionParams::default(),
+            thermal: ThermalParams::default(),
+            hydrology: HydrologyParams::default(),
+            temperature: TemperatureParams::default(),
+            rainfall: RainfallParams::default(),
+            chunks: ChunkParams::default(),
+            terrain_mesh: TerrainMeshParams::default(),
+            water: WaterParams::default(),
+            texturing: TexturingParams::default(),
+        }
+    }
+}
+
+impl GenerationSettings {
+    pub fn from_ron(text: &str) -> Result<Self, SettingsError> {
+        let settings: GenerationSettings = ron::from_str(text).map_err(|err| SettingsError::Parse(err.to_string()))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn to_ron(&self) -> String {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).expect("settings are always serializable")
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SettingsError> {
+        Self::from_ron(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SettingsError> {
+        fs::write(path, self.to_ron())?;
+        Ok(())
+    }
+
+    /// Loads `SETTINGS_PATH`, falling back to the defaults if it is missing or broken
+    pub fn load_or_default() -> Self {
+        Self::load(SETTINGS_PATH).unwrap_or_else(|err| {
+            eprintln!("Using default settings, {}", err);
+            Self::default()
+        })
+    }
+
+    /// Rejects values the stages cannot work with
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let invalid = |message: &str| Err(SettingsError::Invalid(message.to_string()));
+        if self.world_size < 2 {
+            return invalid("world_size must be at least 2");
+        }
+        if !(self.sea_level > 0.0 && self.sea_level < MAX_HEIGHT) {
+            return invalid("sea_level must be between 0 and MAX_HEIGHT");
+        }
+        if self.islands.chunk_sizes.contains(&0) || self.islands.deep_ocean_chunk_size == 0 {
+            return invalid("island chunk sizes must be positive");
+        }
+        let share = |value: f32| (0.0..=1.0).contains(&value);
+        let erosion = &self.erosion;
+        if !(erosion.capacity > 0.0 && erosion.min_capacity >= 0.0 && erosion.gravity > 0.0) {
+            return invalid("erosion capacity and gravity must be positive, min_capacity must not be negative");
+        }
+        if !((0.0..1.0).contains(&erosion.inertia) && share(erosion.deposition) && share(erosion.erosion) && share(erosion.evaporation)) {
+            return invalid("erosion inertia must be at least 0 and below 1, deposition, erosion and evaporation between 0 and 1");
+        }
+        if !(self.thermal.talus_angle > 0.0 && self.thermal.talus_angle < 90.0 && (0.0..=0.5).contains(&self.thermal.strength)) {
+            return invalid("talus_angle must be between 0 and 90 degrees, thermal strength between 0 and 0.5");
+        }
+        let hydrology = &self.hydrology;
+        if !(hydrology.epsilon > 0.0 && hydrology.river_threshold > 0.0 && hydrology.lake_min_depth >= 0.0 && hydrology.erosion_flux_weight >= 0.0) {
+            return invalid("hydrology epsilon and river_threshold must be positive, lake_min_depth and erosion_flux_weight must not be negative");
+        }
+        if !(self.temperature.lapse_rate >= 0.0 && self.temperature.lapse_rate.is_finite()) {
+            return invalid("lapse_rate must be finite and not negative");
+        }
+        let rainfall = &self.rainfall;
+        if !(rainfall.rain_rate > 0.0 && rainfall.rainfall_scale > 0.0 && rainfall.orographic_rate >= 0.0 && rainfall.initial_moisture >= 0.0) {
+            return invalid("rain_rate and rainfall_scale must be positive, orographic_rate and initial_moisture must not be negative");
+        }
+        if !(share(rainfall.evaporation) && share(rainfall.land_evaporation) && rainfall.turbulence >= 0.0) {
+            return invalid("evaporation and land_evaporation must be between 0 and 1, turbulence must not be negative");
+        }
+        if self.chunks.chunk_size == 0 {
+            return invalid("chunk_size must be positive");
+        }
+        if self.terrain_mesh.chunk_size == 0 || self.terrain_mesh.tiles_per_vertex == 0 {
+            return invalid("terrain mesh chunk_size and tiles_per_vertex must be positive");
+        }
+        let lod_distance = self.terrain_mesh.lod_distance;
+        if !(lod_distance > 0.0 && lod_distance.is_finite() && (0.0..1.0).contains(&self.terrain_mesh.lod_hysteresis)) {
+            return invalid("lod_distance must be positive and lod_hysteresis between 0 and 1");
+        }
+        let opacities = [self.water.shallow_opacity, self.water.deep_opacity];
+        if !(self.water.full_depth > 0.0 && opacities.iter().all(|opacity| (0.0..=1.0).contains(opacity))) {
+            return invalid("water full_depth must be positive and its opacities between 0 and 1");
+        }
+        let texturing = &self.texturing;
+        let ordered = |[start, end]: [f32; 2]| start >= 0.0 && start < end;
+        if !(texturing.texture_size > 0.0 && ordered(texturing.rock_slope) && ordered(texturing.snow_height) && texturing.snow_max_slope > 0.0) {
+            return invalid("texture_size and snow_max_slope must be positive, rock_slope and snow_height must start before they end");
+        }
+        Ok(())
+    }
+}
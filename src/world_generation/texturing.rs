@@ -0,0 +1,228 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use super::biomes::BiomeRegistry;
+// Textures blended over the terrain in the app
+// Every biome gets a tiling texture, two more layers hold rock and snow.
+// A layer is read from `<name>.png` in `TEXTURE_DIR`, biomes without a file get noise in their color.
+// The app blends the textures of the tiles around each pixel by their biome,
+// then covers steep slopes with rock and high ground with snow
+
+/// Directory of the texture files, relative to the working directory
+pub const TEXTURE_DIR: &str = "assets/textures/terrain";
+/// Texels per side of a texture layer, texture files are scaled down to it
+pub const TEXTURE_TEXELS: u32 = 256;
+/// File name, color and noise contrast of the layers after the biomes
+const ROCK: (&str, [u8; 3], f32) = ("Rock", [118, 112, 106], 0.5);
+const SNOW: (&str, [u8; 3], f32) = ("Snow", [236, 240, 246], 0.08);
+/// Noise contrast of the biome layers
+const BIOME_CONTRAST: f32 = 0.25;
+
+/// Tunables of the terrain textures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TexturingParams {
+    /// Tiles covered by one repetition of a texture
+    pub texture_size: f32,
+    /// Slopes (rise over run) at which rock starts to show and covers the ground
+    pub rock_slope: [f32; 2],
+    /// Heights at which snow starts to show and covers the ground
+    pub snow_height: [f32; 2],
+    /// Slope (rise over run) above which snow does not stick
+    pub snow_max_slope: f32,
+}
+
+impl Default for TexturingParams {
+    fn default() -> Self {
+        TexturingParams {
+            texture_size: 16.0,
+            rock_slope: [0.8, 1.4],
+            snow_height: [200.0, 235.0],
+            snow_max_slope: 1.2,
+        }
+    }
+}
+
+/// How far a surface with the given slope (rise over run) is from flat, 0.0 is flat and 1.0 vertical
+pub fn steepness(slope: f32) -> f32 {
+    1.0 - 1.0 / (1.0 + slope * slope).sqrt()
+}
+
+/// Mip levels of a texture layer, down to a single texel
+pub fn mip_levels() -> u32 {
+    TEXTURE_TEXELS.ilog2() + 1
+}
+
+/// Layer of the rock texture, the snow follows it
+pub fn rock_layer(registry: &BiomeRegistry) -> u32 {
+    registry.iter().count() as u32
+}
+
+/// sRGB RGBA texels of all layers, a layer per biome in registry order followed by rock and snow.
+/// Every layer holds all of its mip levels before the next layer starts.
+/// Layers are read from the texture files in `dir`, a layer without a usable file is generated.
+pub fn texture_layers(registry: &BiomeRegistry, dir: &Path) -> Vec<u8> {
+    let layers = registry
+        .iter()
+        .map(|(_, def)| (def.name.as_str(), def.color, BIOME_CONTRAST))
+        .chain([ROCK, SNOW]);
+    layers
+        .enumerate()
+        .flat_map(|(seed, (name, color, contrast))| {
+            let path = dir.join(format!("{}.png", name));
+            let level = match read_texture(&path) {
+                Ok(level) => level,
+                Err(err) => {
+                    // Missing files are expected, only broken ones are worth a message
+                    if err.kind() != io::ErrorKind::NotFound {
+                        eprintln!("Generating the {} texture, could not read {}: {}", name, path.display(), err);
+                    }
+                    tinted_noise(color, contrast, seed as u64)
+                }
+            };
+            mip_chain(level)
+        })
+        .collect()
+}
+
+/// Reads a square 8-bit RGB or RGBA PNG and averages it down to `TEXTURE_TEXELS`, alpha is ignored
+fn read_texture(path: &Path) -> io::Result<Vec<[f32; 3]>> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(io::Error::other)?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).map_err(io::Error::other)?;
+    let channels = match info.color_type {
+        png::ColorType::Rgb => 3,
+        png::ColorType::Rgba => 4,
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} images are not supported, use RGB or RGBA", other))),
+    };
+    let (width, size) = (info.width as usize, TEXTURE_TEXELS as usize);
+    if info.height != info.width || width % size != 0 {
+        let message = format!("the image must be square with a multiple of {} pixels per side", size);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+
+    // Every texel averages a block of `scale` x `scale` pixels
+    let scale = width / size;
+    let texels = (0..size * size)
+        .map(|i| {
+            let (row, column) = (i / size * scale, i % size * scale);
+            let mut average = [0.0; 3];
+            for y in row..row + scale {
+                for x in column..column + scale {
+                    let pixel = &data[(y * width + x) * channels..];
+                    for (sum, &channel) in average.iter_mut().zip(pixel) {
+                        *sum += channel as f32 / (scale * scale) as f32;
+                    }
+                }
+            }
+            average
+        })
+        .collect();
+    Ok(texels)
+}
+
+/// Texels of a generated layer, `color` roughened by tiling noise
+fn tinted_noise(color: [u8; 3], contrast: f32, seed: u64) -> Vec<[f32; 3]> {
+    tiling_noise(TEXTURE_TEXELS as usize, seed)
+        .iter()
+        .map(|&value| color.map(|channel| channel as f32 * (1.0 + contrast * (value - 0.5))))
+        .collect()
+}
+
+/// RGBA texels of a layer followed by its mip levels
+fn mip_chain(mut level: Vec<[f32; 3]>) -> Vec<u8> {
+    let mut width = TEXTURE_TEXELS as usize;
+    let mut texels = Vec::new();
+    loop {
+        for color in &level {
+            texels.extend(color.map(|channel| channel.round().clamp(0.0, 255.0) as u8));
+            texels.push(255);
+        }
+        if width == 1 {
+            return texels;
+        }
+        // Next level averages blocks of 2 x 2 texels
+        let half = width / 2;
+        level = (0..half * half)
+            .map(|i| {
+                let (row, column) = (i / half * 2, i % half * 2);
+                let block = [row * width + column, row * width + column + 1, (row + 1) * width + column, (row + 1) * width + column + 1];
+                let mut average = [0.0; 3];
+                for texel in block {
+                    for (sum, channel) in average.iter_mut().zip(level[texel]) {
+                        *sum += channel / 4.0;
+                    }
+                }
+                average
+            })
+            .collect();
+        width = half;
+    }
+}
+
+/// Value noise in 0.0 - 1.0 over `size` x `size` texels that wraps around at the edges
+fn tiling_noise(size: usize, seed: u64) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut noise = vec![0.0; size * size];
+    let mut amplitude = 0.5;
+    let mut total = 0.0;
+    // Every octave has a whole number of cells across the texture, so it tiles
+    for cells in [4, 8, 16, 32] {
+        let lattice: Vec<f32> = (0..cells * cells).map(|_| rng.random::<f32>()).collect();
+        let at = |x: usize, z: usize| lattice[(z % cells) * cells + x % cells];
+        for (i, value) in noise.iter_mut().enumerate() {
+            let x = (i % size) as f32 * cells as f32 / size as f32;
+            let z = (i / size) as f32 * cells as f32 / size as f32;
+            let (x0, z0) = (x.floor() as usize, z.floor() as usize);
+            // Smoothstep between the lattice points
+            let (tx, tz) = (x.fract(), z.fract());
+            let (tx, tz) = (tx * tx * (3.0 - 2.0 * tx), tz * tz * (3.0 - 2.0 * tz));
+            let top = at(x0, z0) + (at(x0 + 1, z0) - at(x0, z0)) * tx;
+            let bottom = at(x0, z0 + 1) + (at(x0 + 1, z0 + 1) - at(x0, z0 + 1)) * tx;
+            *value += amplitude * (top + (bottom - top) * tz);
+        }
+        total += amplitude;
+        amplitude *= 0.5;
+    }
+    noise.iter().map(|value| value / total).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texture_files_replace_the_generated_layers() {
+        let registry = BiomeRegistry::global();
+        let (_, first) = registry.iter().next().unwrap();
+        let dir = std::env::temp_dir().join(format!("terrain-textures-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Twice the layer size in alternating black and white columns, so every texel averages to gray
+        let width = TEXTURE_TEXELS * 2;
+        let mut encoder = png::Encoder::new(File::create(dir.join(format!("{}.png", first.name))).unwrap(), width, width);
+        encoder.set_color(png::ColorType::Rgb);
+        let pixels: Vec<u8> = (0..width * width).flat_map(|i| [if i % 2 == 0 { 0 } else { 255 }; 3]).collect();
+        encoder.write_header().unwrap().write_image_data(&pixels).unwrap();
+        // Not square, falls back to the generated layer
+        let mut encoder = png::Encoder::new(File::create(dir.join("Rock.png")).unwrap(), TEXTURE_TEXELS, 1);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.write_header().unwrap().write_image_data(&vec![0; TEXTURE_TEXELS as usize * 3]).unwrap();
+
+        let layers = texture_layers(registry, &dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let layer_bytes = mip_chain(vec![[0.0; 3]; (TEXTURE_TEXELS * TEXTURE_TEXELS) as usize]).len();
+        assert_eq!(layers.len(), layer_bytes * (rock_layer(registry) as usize + 2));
+        assert!(layers[..layer_bytes].chunks(4).all(|texel| texel == [128, 128, 128, 255]));
+
+        let generated = texture_layers(registry, Path::new("no such directory"));
+        assert_ne!(layers[..layer_bytes], generated[..layer_bytes]);
+        assert_eq!(layers[layer_bytes..], generated[layer_bytes..]);
+    }
+}
@@ -0,0 +1,87 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use super::progress::{Cancelled, Progress};
+use super::settings::GenerationSettings;
+use super::tile::{for_each_neighbor, WorldMap};
+// Thermal weathering
+// Wherever a slope is steeper than the talus angle, material crumbles down to the lower neighbours
+// This leaves scree slopes at the foot of cliffs and softens the edges of the island chunks
+
+/// Tunables of the thermal erosion stage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThermalParams {
+    /// Number of relaxation steps over the whole map
+    pub iterations: u32,
+    /// Steepest stable slope in degrees
+    pub talus_angle: f32,
+    /// Share of the excess material moved per iteration, 0.0 - 0.5 keeps it stable
+    pub strength: f32,
+}
+
+impl Default for ThermalParams {
+    fn default() -> Self {
+        ThermalParams {
+            iterations: 10,
+            talus_angle: 40.0,
+            strength: 0.5,
+        }
+    }
+}
+
+/// Moves material downhill until no slope is steeper than the talus angle
+pub fn relax_slopes(world_map: &mut WorldMap, params: &ThermalParams, progress: &Progress) -> Result<(), Cancelled> {
+    let size = world_map.get_size() as usize;
+    let talus = params.talus_angle.to_radians().tan();
+
+    let heights = &mut world_map.heights;
+    // Material leaving each tile and the summed excess over all its lower neighbours
+    let mut moved = vec![0.0f32; size * size];
+    let mut total_excess = vec![0.0f32; size * size];
+
+    for iteration in 0..params.iterations {
+        moved
+            .par_chunks_mut(size)
+            .zip(total_excess.par_chunks_mut(size))
+            .enumerate()
+            .for_each(|(x, (moved_row, excess_row))| {
+                for z in 0..size {
+                    let height = heights[x * size + z];
+                    let mut total = 0.0;
+                    let mut largest = 0.0f32;
+                    for_each_neighbor(x * size + z, size, |neighbor, distance| {
+                        let excess = height - heights[neighbor] - talus * distance;
+                        if excess > 0.0 {
+                            total += excess;
+                            largest = largest.max(excess);
+                        }
+                    });
+                    moved_row[z] = largest * params.strength;
+                    excess_row[z] = total;
+                }
+            });
+
+        // Every tile gathers what its higher neighbours shed, so the rows can be updated in parallel
+        let previous = heights.clone();
+        heights.par_chunks_mut(size).enumerate().for_each(|(x, row)| {
+            for (z, height) in row.iter_mut().enumerate() {
+                let i = x * size + z;
+                let mut gained = 0.0;
+                for_each_neighbor(i, size, |neighbor, distance| {
+                    let excess = previous[neighbor] - previous[i] - talus * distance;
+                    if excess > 0.0 {
+                        gained += moved[neighbor] * excess / total_excess[neighbor];
+                    }
+                });
+                *height += gained - moved[i];
+            }
+        });
+        progress.update((iteration + 1) as f32 / params.iterations as f32)?;
+    }
+    Ok(())
+}
+
+pub fn thermal_stack(world_map: &mut WorldMap, settings: &GenerationSettings, progress: &Progress) -> Result<(), Cancelled> {
+    progress.begin("Weathering slopes")?;
+    relax_slopes(world_map, &settings.thermal, progress)
+}
@@ -1,94 +1,318 @@
-use bevy::{color::{ColorToComponents, Srgba}, ecs::system::Resource};
+use std::fmt;
+use std::iter::StepBy;
+use std::ops::Range;
+#[cfg(feature = "render")]
+use bevy::ecs::system::Resource;
+use rayon::prelude::*;
+use serde::{Serialize, Serializer};
+use super::biomes::BiomeRegistry;
 
 pub const OCEAN: f32 = 30.0;
 pub const DEEP_OCEAN: f32 = 10.0;
+/// Default sea level, every map carries its own, see `WorldMap::sea_level`
 pub const SEA_LEVEL: f32 = 60.0;
 pub const MAX_HEIGHT: f32 = 280.0;
 
-#[derive(Debug, Clone, Copy)]
-pub enum Biome {
-    // Land Biomes
-    Grassland,
-    // Aquatic Biomes
-    DeepOcean,
-    CoralReef,
-    ShallowSea,
-    ShallowOcean,
-}
+/// ID of a biome in the `BiomeRegistry` loaded from `resources/biomes.txt`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Biome(pub u8);
 
 impl Biome {
+    pub fn name(&self) -> &'static str {
+        &BiomeRegistry::global().get(*self).name
+    }
+
     pub fn color(&self) -> [f32; 4] {
-        match self {
-            // Land Biomes
-            Biome::Grassland => Srgba::rgba_u8(91, 235, 52, 255).to_f32_array(),
-
-            // Aquatic Biomes
-            Biome::DeepOcean => Srgba::rgba_u8(12, 63, 173, 255).to_f32_array(),
-            Biome::CoralReef => Srgba::rgba_u8(12, 173, 146, 255).to_f32_array(),
-            Biome::ShallowSea => Srgba::rgba_u8(30, 231, 235, 255).to_f32_array(),
-            Biome::ShallowOcean => Srgba::rgba_u8(48, 109, 240, 255).to_f32_array(),
+        let [r, g, b] = BiomeRegistry::global().get(*self).color;
+        [r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0, 1.0]
+    }
+}
+
+/// Biomes are written by name, their IDs depend on the order of `resources/biomes.txt`
+impl Serialize for Biome {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl fmt::Display for Biome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Noise input of the local tile (x, z) of a map starting at `origin`.
+/// Global tile coordinates are divided by `GenerationSettings::world_size`, so noise and latitude
+/// span the same range whatever the world size is, and a chunk samples the same noise as the whole world.
+/// Usable while the layers of the map are borrowed.
+pub fn noise_coords(origin: (i64, i64), world_size: u32, x: usize, z: usize) -> [f64; 2] {
+    [
+        (origin.0 + x as i64) as f64 / world_size as f64,
+        (origin.1 + z as i64) as f64 / world_size as f64,
+    ]
+}
+
+/// Marks tiles without a biome in `WorldMap::biomes`
+pub const NO_BIOME: u8 = u8::MAX;
+
+/// Range of the temperatures, stored in `WorldMap::temperatures` with `quantize`
+pub const TEMPERATURE_RANGE: [f32; 2] = [-1.0, 1.0];
+/// Range of the rainfall, stored in `WorldMap::rainfall` with `quantize`
+pub const RAINFALL_RANGE: [f32; 2] = [0.0, 1.0];
+
+/// Stores a value of `range` in 16 bits, values outside of it are clamped.
+/// Steps are about 1/65535 of the range, far finer than the biome table tells apart.
+pub fn quantize(value: f32, [min, max]: [f32; 2]) -> u16 {
+    ((value.clamp(min, max) - min) / (max - min) * u16::MAX as f32).round() as u16
+}
+
+/// Inverse of `quantize`
+pub fn dequantize(value: u16, [min, max]: [f32; 2]) -> f32 {
+    min + value as f32 / u16::MAX as f32 * (max - min)
+}
+
+/// Offsets and distances of the eight neighbours of a tile
+const NEIGHBOR_OFFSETS: [(isize, isize, f32); 8] = [
+    (-1, -1, std::f32::consts::SQRT_2),
+    (0, -1, 1.0),
+    (1, -1, std::f32::consts::SQRT_2),
+    (-1, 0, 1.0),
+    (1, 0, 1.0),
+    (-1, 1, std::f32::consts::SQRT_2),
+    (0, 1, 1.0),
+    (1, 1, std::f32::consts::SQRT_2),
+];
+
+/// Calls `f` with the index and distance of every neighbour of the tile at index `i` inside a map of `size` tiles.
+/// Usable while the layers of the map are borrowed.
+pub fn for_each_neighbor(i: usize, size: usize, mut f: impl FnMut(usize, f32)) {
+    let (x, z) = (i / size, i % size);
+    for &(dx, dz, distance) in &NEIGHBOR_OFFSETS {
+        let nx = x as isize + dx;
+        let nz = z as isize + dz;
+        if nx >= 0 && nz >= 0 && (nx as usize) < size && (nz as usize) < size {
+            f(nx as usize * size + nz as usize, distance);
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Copy of all layers of a single tile
+#[derive(Debug, Clone, Copy, Serialize)]
 pub struct Tile {
     pub height: f32,
     pub temperature: f32,
     pub rainfall: f32,
-    pub biome: Option<Biome>
+    pub biome: Option<Biome>,
+    /// Material deposited (positive) or removed (negative) by hydraulic erosion
+    pub sediment: Option<f32>,
+    /// Water that flowed over the tile during hydraulic erosion
+    pub water_flux: Option<f32>,
+    /// Height of the water surface, equal to `height` on dry land
+    pub water_level: f32,
+    pub river: bool,
+    /// Runoff of the tiles that drain through this tile, including itself
+    pub flow: Option<f32>,
 }
 
-impl Tile {
-    pub fn new() -> Self {
-        Tile { height: 0.0, temperature: 0.0, rainfall: 0.0, biome: None}
-    }
-}
-
-#[derive(Debug, Clone, Resource)]
+/// The world as one flat plane per layer.
+/// Tile (x, z) is stored at `x * size + z`, so a row of constant x is contiguous.
+/// Tile (0, 0) sits at the global tile coordinates `origin`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "render", derive(Resource))]
 pub struct WorldMap {
     size: u32,
-    pub tiles: Vec<Vec<Tile>>
+    origin: (i64, i64),
+    sea_level: f32,
+    pub heights: Vec<f32>,
+    /// Quantized over `TEMPERATURE_RANGE`, see `temperature_at`
+    pub temperatures: Vec<u16>,
+    /// Quantized over `RAINFALL_RANGE`, see `rainfall_at`
+    pub rainfall: Vec<u16>,
+    /// `Biome` IDs, `NO_BIOME` until biomes are assigned
+    pub biomes: Vec<u8>,
+    pub water_levels: Vec<f32>,
+    pub rivers: Vec<bool>,
+    /// Only kept if `ErosionParams::keep_layers` is set
+    pub sediment: Option<Vec<f32>>,
+    pub water_flux: Option<Vec<f32>>,
+    /// Only kept if `HydrologyParams::keep_flow` is set
+    pub flows: Option<Vec<f32>>,
 }
 
 impl WorldMap {
     pub fn new(size: u32) -> Self {
-        WorldMap { size: size, tiles: vec![vec![Tile::new(); size as usize]; size as usize] }
+        Self::with_origin(size, (0, 0))
+    }
+
+    pub fn with_origin(size: u32, origin: (i64, i64)) -> Self {
+        let tiles = size as usize * size as usize;
+        WorldMap {
+            size,
+            origin,
+            sea_level: SEA_LEVEL,
+            heights: vec![0.0; tiles],
+            temperatures: vec![quantize(0.0, TEMPERATURE_RANGE); tiles],
+            rainfall: vec![0; tiles],
+            biomes: vec![NO_BIOME; tiles],
+            water_levels: vec![0.0; tiles],
+            rivers: vec![false; tiles],
+            sediment: None,
+            water_flux: None,
+            flows: None,
+        }
     }
 
-    pub fn get_height(&mut self, x: usize, z: usize) -> f32 {
-        self.tiles[x][z].height
+    pub fn get_size(&self) -> u32 {
+        self.size
     }
 
-    pub fn set_height(&mut self, x: usize, z: usize, height: f32) {
-        self.tiles[x][z].height = height
+    pub fn origin(&self) -> (i64, i64) {
+        self.origin
     }
 
-    pub fn get_temperature(&mut self, x: usize, z: usize) -> f32 {
-        self.tiles[x][z].temperature
+    /// Height below which tiles are ocean
+    pub fn sea_level(&self) -> f32 {
+        self.sea_level
     }
 
-    pub fn set_temperature(&mut self, x: usize, z: usize, temperature: f32) {
-        self.tiles[x][z].temperature = temperature
+    pub fn set_sea_level(&mut self, sea_level: f32) {
+        self.sea_level = sea_level
     }
 
-    pub fn get_rainfall(&mut self, x: usize, z: usize) -> f32 {
-        self.tiles[x][z].rainfall
+    /// Global tile coordinates of a local tile
+    pub fn global_coords(&self, x: usize, z: usize) -> (i64, i64) {
+        (self.origin.0 + x as i64, self.origin.1 + z as i64)
+    }
+
+    /// Copies the square of `size` tiles starting at local tile (x0, z0) into its own map
+    pub fn crop(&self, x0: usize, z0: usize, size: u32) -> WorldMap {
+        let mut cropped = WorldMap::with_origin(size, self.global_coords(x0, z0));
+        cropped.sea_level = self.sea_level;
+        let size = size as usize;
+        let rows = |plane: &[f32]| (0..size).flat_map(|x| &plane[self.index(x0 + x, z0)..self.index(x0 + x, z0 + size)]).copied().collect();
+        cropped.sediment = self.sediment.as_deref().map(rows);
+        cropped.water_flux = self.water_flux.as_deref().map(rows);
+        cropped.flows = self.flows.as_deref().map(rows);
+        for x in 0..size {
+            let source = self.index(x0 + x, z0)..self.index(x0 + x, z0 + size);
+            let target = cropped.row(x);
+            cropped.heights[target.clone()].copy_from_slice(&self.heights[source.clone()]);
+            cropped.temperatures[target.clone()].copy_from_slice(&self.temperatures[source.clone()]);
+            cropped.rainfall[target.clone()].copy_from_slice(&self.rainfall[source.clone()]);
+            cropped.biomes[target.clone()].copy_from_slice(&self.biomes[source.clone()]);
+            cropped.water_levels[target.clone()].copy_from_slice(&self.water_levels[source.clone()]);
+            cropped.rivers[target].copy_from_slice(&self.rivers[source]);
+        }
+        cropped
+    }
+
+    /// Copies every tile of `other` into this map, starting at local tile (x0, z0)
+    pub fn paste(&mut self, other: &WorldMap, x0: usize, z0: usize) {
+        let tiles = self.heights.len();
+        let size = other.size as usize;
+        for x in 0..size {
+            let source = other.row(x);
+            let target = self.index(x0 + x, z0)..self.index(x0 + x, z0 + size);
+            self.heights[target.clone()].copy_from_slice(&other.heights[source.clone()]);
+            self.temperatures[target.clone()].copy_from_slice(&other.temperatures[source.clone()]);
+            self.rainfall[target.clone()].copy_from_slice(&other.rainfall[source.clone()]);
+            self.biomes[target.clone()].copy_from_slice(&other.biomes[source.clone()]);
+            self.water_levels[target.clone()].copy_from_slice(&other.water_levels[source.clone()]);
+            self.rivers[target.clone()].copy_from_slice(&other.rivers[source.clone()]);
+            for (plane, other_plane) in [(&mut self.sediment, &other.sediment), (&mut self.water_flux, &other.water_flux), (&mut self.flows, &other.flows)] {
+                if let Some(other_plane) = other_plane {
+                    plane.get_or_insert_with(|| vec![0.0; tiles])[target.clone()].copy_from_slice(&other_plane[source.clone()]);
+                }
+            }
+        }
+    }
+
+    pub fn index(&self, x: usize, z: usize) -> usize {
+        x * self.size as usize + z
+    }
+
+    /// Indices of the row of tiles with the given x, use it to slice any layer
+    pub fn row(&self, x: usize) -> Range<usize> {
+        let size = self.size as usize;
+        x * size..(x + 1) * size
+    }
+
+    /// Indices of the column of tiles with the given z
+    pub fn column(&self, z: usize) -> StepBy<Range<usize>> {
+        let size = self.size as usize;
+        (z..size * size).step_by(size)
+    }
+
+    pub fn tile(&self, x: usize, z: usize) -> Tile {
+        self.tile_at(self.index(x, z))
+    }
+
+    pub fn tile_at(&self, i: usize) -> Tile {
+        Tile {
+            height: self.heights[i],
+            temperature: self.temperature_at(i),
+            rainfall: self.rainfall_at(i),
+            biome: (self.biomes[i] != NO_BIOME).then_some(Biome(self.biomes[i])),
+            sediment: self.sediment.as_ref().map(|sediment| sediment[i]),
+            water_flux: self.water_flux.as_ref().map(|water_flux| water_flux[i]),
+            water_level: self.water_levels[i],
+            river: self.rivers[i],
+            flow: self.flows.as_ref().map(|flows| flows[i]),
+        }
+    }
+
+    /// All tiles in storage order, together with their index
+    pub fn par_tiles(&self) -> impl IndexedParallelIterator<Item = (usize, Tile)> + '_ {
+        (0..self.heights.len()).into_par_iter().map(|i| (i, self.tile_at(i)))
+    }
+
+    pub fn is_ocean(&self, i: usize) -> bool {
+        self.heights[i] < self.sea_level
+    }
+
+    /// Filled depression above the sea, rivers also sit below their water level but are no lakes
+    pub fn is_lake(&self, i: usize) -> bool {
+        !self.is_ocean(i) && !self.rivers[i] && self.water_levels[i] > self.heights[i]
+    }
+
+    pub fn get_height(&self, x: usize, z: usize) -> f32 {
+        self.heights[self.index(x, z)]
+    }
+
+    pub fn set_height(&mut self, x: usize, z: usize, height: f32) {
+        let i = self.index(x, z);
+        self.heights[i] = height
+    }
+
+    pub fn temperature_at(&self, i: usize) -> f32 {
+        dequantize(self.temperatures[i], TEMPERATURE_RANGE)
+    }
+
+    pub fn rainfall_at(&self, i: usize) -> f32 {
+        dequantize(self.rainfall[i], RAINFALL_RANGE)
     }
 
     pub fn set_rainfall(&mut self, x: usize, z: usize, rainfall: f32) {
-        self.tiles[x][z].rainfall = rainfall
+        let i = self.index(x, z);
+        self.rainfall[i] = quantize(rainfall, RAINFALL_RANGE)
     }
 
-    pub fn get_size(&mut self) -> u32 {
-        self.size
+    /// Temperatures of all tiles in storage order
+    pub fn temperature_plane(&self) -> Vec<f32> {
+        self.temperatures.par_iter().map(|&temperature| dequantize(temperature, TEMPERATURE_RANGE)).collect()
+    }
+
+    /// Rainfall of all tiles in storage order
+    pub fn rainfall_plane(&self) -> Vec<f32> {
+        self.rainfall.par_iter().map(|&rainfall| dequantize(rainfall, RAINFALL_RANGE)).collect()
     }
 
-    pub fn get_biome(&mut self, x: usize, z: usize) -> Option<Biome> {
-        self.tiles[x][z].biome
+    pub fn get_water_level(&self, x: usize, z: usize) -> f32 {
+        self.water_levels[self.index(x, z)]
     }
 
-    pub fn set_biome(&mut self, x: usize, z: usize, biome: Biome) {
-        self.tiles[x][z].biome = Some(biome)
+    pub fn get_biome(&self, x: usize, z: usize) -> Option<Biome> {
+        let id = self.biomes[self.index(x, z)];
+        (id != NO_BIOME).then_some(Biome(id))
     }
-}
\ No newline at end of file
+}
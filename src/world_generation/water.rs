@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use super::meshing::{samples, TerrainChunk, TerrainMesh};
+use super::tile::WorldMap;
+// Water surfaces shown over the terrain
+// The sea covers the ocean tiles at the sea level, lakes and rivers sit at their own water level
+// Water meshes use the chunks of the terrain, their vertices are colored by the depth of the water below them
+
+/// Tunables of the water shown in the app
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WaterParams {
+    /// Color of the water along the shore, linear RGB
+    pub shallow_color: [f32; 3],
+    /// Color of the water from `full_depth` down, linear RGB
+    pub deep_color: [f32; 3],
+    pub shallow_opacity: f32,
+    pub deep_opacity: f32,
+    /// Depth at which the water gets its deep color and opacity
+    pub full_depth: f32,
+    /// Ripples the light on the water with a scrolling normal map
+    pub waves: bool,
+}
+
+impl Default for WaterParams {
+    fn default() -> Self {
+        WaterParams {
+            shallow_color: [0.1, 0.55, 0.6],
+            deep_color: [0.01, 0.06, 0.2],
+            shallow_opacity: 0.3,
+            deep_opacity: 0.9,
+            full_depth: 40.0,
+            waves: true,
+        }
+    }
+}
+
+impl WaterParams {
+    /// Vertex color of water `depth` deep, the alpha is its opacity
+    pub fn color(&self, depth: f32) -> [f32; 4] {
+        // Square root so the shore fades quickly and the open water stays even
+        let t = (depth / self.full_depth).clamp(0.0, 1.0).sqrt();
+        let mix = |shallow: f32, deep: f32| shallow + (deep - shallow) * t;
+        [
+            mix(self.shallow_color[0], self.deep_color[0]),
+            mix(self.shallow_color[1], self.deep_color[1]),
+            mix(self.shallow_color[2], self.deep_color[2]),
+            mix(self.shallow_opacity, self.deep_opacity),
+        ]
+    }
+}
+
+/// Height of the water at a tile, `None` on dry land
+pub fn water_surface(world_map: &WorldMap, x: usize, z: usize) -> Option<f32> {
+    let i = world_map.index(x, z);
+    if world_map.is_ocean(i) {
+        Some(world_map.sea_level())
+    } else if world_map.water_levels[i] > world_map.heights[i] {
+        Some(world_map.water_levels[i])
+    } else {
+        None
+    }
+}
+
+/// Water surface of a chunk with a vertex every `step` tiles, positions are relative to the first tile of the chunk like `chunk_mesh`.
+/// Empty if none of the vertices is under water.
+pub fn water_mesh(world_map: &WorldMap, chunk: &TerrainChunk, step: usize, params: &WaterParams) -> TerrainMesh {
+    let step = step.max(1);
+    let columns = samples(chunk.x, chunk.x + chunk.width, step);
+    let rows = samples(chunk.z, chunk.z + chunk.depth, step);
+    let vertices = columns.len();
+    let levels: Vec<Option<f32>> = rows
+        .iter()
+        .flat_map(|&z| columns.iter().map(move |&x| water_surface(world_map, x, z)))
+        .collect();
+
+    // Dry vertices next to water take the highest level around them, so the water runs into the shore and the terrain cuts it off.
+    // Only neighbours sharing a triangle count, that is all but the diagonal the triangles do not cross.
+    let neighbours = [(-1, 0), (1, 0), (0, -1), (0, 1), (1, -1), (-1, 1)];
+    let mut mesh = TerrainMesh::default();
+    let mut mesh_index = vec![None; levels.len()];
+    for (row, &z) in rows.iter().enumerate() {
+        for (column, &x) in columns.iter().enumerate() {
+            let i = row * vertices + column;
+            let level = levels[i].or_else(|| {
+                neighbours
+                    .iter()
+                    .filter_map(|&(dc, dr)| {
+                        let (c, r) = (column.checked_add_signed(dc)?, row.checked_add_signed(dr)?);
+                        (c < vertices && r < rows.len()).then(|| levels[r * vertices + c]).flatten()
+                    })
+                    .reduce(f32::max)
+            });
+            let Some(level) = level else {
+                continue;
+            };
+            mesh_index[i] = Some(mesh.positions.len() as u32);
+            mesh.positions.push([(x - chunk.x) as f32, level, (z - chunk.z) as f32]);
+            mesh.normals.push([0.0, 1.0, 0.0]);
+            mesh.colors.push(params.color((level - world_map.get_height(x, z)).max(0.0)));
+        }
+    }
+
+    // Same triangles as the terrain, kept if one of their corners is under water
+    for row in 0..rows.len() - 1 {
+        for column in 0..vertices - 1 {
+            let i = row * vertices + column;
+            let below = i + vertices;
+            for triangle in [[i, below, i + 1], [i + 1, below, below + 1]] {
+                if !triangle.iter().any(|&v| levels[v].is_some()) {
+                    continue;
+                }
+                if let [Some(a), Some(b), Some(c)] = triangle.map(|v| mesh_index[v]) {
+                    mesh.indices.extend_from_slice(&[a, b, c]);
+                }
+            }
+        }
+    }
+    mesh
+}